@@ -0,0 +1,277 @@
+//! Aggregation of DHT sensor readings over tumbling time windows.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::messages::*;
+use super::units::TemperatureUnit;
+use super::Result;
+
+/// Longest aggregation window, a year of 366 days.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// Configuration of the aggregation stage.
+///
+/// Example configuration YAML (inside of `logger_config`):
+/// ```yaml
+/// aggregate:
+///   # Length of the aggregation window in seconds, at most a year (see `MAX_INTERVAL`).
+///   interval: 60
+///   # Sinks receiving aggregates instead of raw readings.
+///   sinks:
+///     - udp
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AggregateConfig {
+    pub interval: u64,
+    pub sinks: Vec<String>,
+}
+
+/// Running statistics of a single field using Welford's algorithm.
+#[derive(Copy, Clone, Debug)]
+struct RunningStats {
+    count: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl RunningStats {
+    fn new() -> RunningStats {
+        RunningStats {
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    fn push(&mut self, value: f32) {
+        let value = value as f64;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn finish(&self) -> FieldAggregate {
+        FieldAggregate {
+            count: self.count,
+            min: self.min as f32,
+            max: self.max as f32,
+            mean: self.mean as f32,
+            stddev: (self.m2 / self.count as f64).sqrt() as f32,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SensorStats {
+    temperature: RunningStats,
+    humidity: RunningStats,
+    heat_index: RunningStats,
+    extra: BTreeMap<String, RunningStats>,
+}
+
+impl SensorStats {
+    fn new() -> SensorStats {
+        SensorStats {
+            temperature: RunningStats::new(),
+            humidity: RunningStats::new(),
+            heat_index: RunningStats::new(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, data: &SensorData) {
+        self.temperature.push(data.temperature);
+        self.humidity.push(data.humidity);
+        self.heat_index.push(data.heat_index);
+        for (name, value) in data.extra.iter() {
+            self.extra
+                .entry(name.clone())
+                .or_insert_with(RunningStats::new)
+                .push(*value);
+        }
+    }
+
+    fn finish(&self) -> SensorAggregate {
+        SensorAggregate {
+            temperature: self.temperature.finish(),
+            humidity: self.humidity.finish(),
+            heat_index: self.heat_index.finish(),
+            extra: self
+                .extra
+                .iter()
+                .map(|(name, stats)| (name.clone(), stats.finish()))
+                .collect(),
+        }
+    }
+}
+
+/// Buffer DHT sensor readings over tumbling windows aligned to wall-clock boundaries.
+///
+/// Windows are aligned to multiples of the interval since the UNIX epoch, so an interval of 60
+/// seconds produces windows starting at the top of every minute.
+pub struct Aggregator {
+    interval: i64,
+    window_start: Option<i64>,
+    unit: TemperatureUnit,
+    sensors: HashMap<String, SensorStats>,
+}
+
+impl Aggregator {
+    /// Create an aggregator with a window length of `interval`, which must be at least a second
+    /// and at most `MAX_INTERVAL`. Returns an `InvalidData` error otherwise.
+    pub fn new(interval: Duration) -> Result<Aggregator> {
+        if interval.as_secs() < 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Aggregation interval must be at least one second",
+            ));
+        }
+        if interval > MAX_INTERVAL {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Aggregation interval must be at most {} seconds",
+                    MAX_INTERVAL.as_secs()
+                ),
+            ));
+        }
+        let interval = interval.as_secs() as i64;
+
+        Ok(Aggregator {
+            interval,
+            window_start: None,
+            unit: TemperatureUnit::Celsius,
+            sensors: HashMap::new(),
+        })
+    }
+
+    /// Add a measurement to the current window. If the measurement belongs to a new window, the
+    /// previous window is finished and its aggregate is returned. Measurements are aggregated in
+    /// the unit of the first measurement of the window.
+    pub fn push(&mut self, measurement: &DhtSensors) -> Option<AggregateSensors> {
+        let window = measurement
+            .timestamp
+            .timestamp()
+            .div_euclid(self.interval)
+            .saturating_mul(self.interval);
+        let finished = match self.window_start {
+            Some(start) if start != window => self.finish(),
+            _ => None,
+        };
+
+        if self.window_start.is_none() {
+            self.unit = measurement.unit;
+        }
+        self.window_start = Some(window);
+        for (label, data) in measurement.data.iter() {
+            self.sensors
+                .entry(label.clone())
+                .or_insert_with(SensorStats::new)
+                .push(&data.to_unit(measurement.unit, self.unit));
+        }
+
+        finished
+    }
+
+    /// Finish the current window, returning its aggregate if any readings were buffered.
+    pub fn finish(&mut self) -> Option<AggregateSensors> {
+        let start = self.window_start.take()?;
+        if self.sensors.is_empty() {
+            return None;
+        }
+
+        let data = self
+            .sensors
+            .drain()
+            .map(|(label, stats)| (label, stats.finish()))
+            .collect();
+
+        Some(AggregateSensors {
+            start: Utc.timestamp_opt(start, 0).unwrap(),
+            end: Utc.timestamp_opt(start + self.interval, 0).unwrap(),
+            unit: self.unit,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(secs: i64, value: f32) -> DhtSensors {
+        let mut data = HashMap::new();
+        data.insert(
+            String::from("test"),
+            SensorData {
+                temperature: value,
+                humidity: value * 2.0,
+                heat_index: value,
+                extra: BTreeMap::from([(String::from("pressure"), value * 10.0)]),
+            },
+        );
+
+        DhtSensors {
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            unit: TemperatureUnit::Celsius,
+            data,
+            errors: HashMap::new(),
+        }
+    }
+
+    // Test that readings within a window are aggregated when the next window starts
+    #[test]
+    fn test_aggregate_window() {
+        let mut aggregator = Aggregator::new(Duration::from_secs(60)).unwrap();
+        assert!(aggregator.push(&reading(120, 1.0)).is_none());
+        assert!(aggregator.push(&reading(150, 2.0)).is_none());
+        assert!(aggregator.push(&reading(179, 3.0)).is_none());
+
+        let aggregate = aggregator.push(&reading(180, 10.0)).unwrap();
+        assert_eq!(aggregate.start.timestamp(), 120);
+        assert_eq!(aggregate.end.timestamp(), 180);
+
+        let data = aggregate.data.get("test").unwrap();
+        assert_eq!(data.temperature.count, 3);
+        assert_eq!(data.temperature.min, 1.0);
+        assert_eq!(data.temperature.max, 3.0);
+        assert_eq!(data.temperature.mean, 2.0);
+        assert!((data.temperature.stddev - (2.0f32 / 3.0).sqrt()).abs() < 1e-6);
+        assert_eq!(data.humidity.mean, 4.0);
+        assert_eq!(data.extra["pressure"].mean, 20.0);
+
+        // The reading that closed the window belongs to the next one
+        let aggregate = aggregator.finish().unwrap();
+        assert_eq!(aggregate.start.timestamp(), 180);
+        assert_eq!(aggregate.data.get("test").unwrap().temperature.count, 1);
+        assert!(aggregator.finish().is_none());
+    }
+
+    // Test that intervals outside of a second and a year are rejected
+    #[test]
+    fn test_aggregate_interval() {
+        for interval in [0, MAX_INTERVAL.as_secs() + 1, u64::MAX] {
+            let err = Aggregator::new(Duration::from_secs(interval))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        let mut aggregator = Aggregator::new(MAX_INTERVAL).unwrap();
+        aggregator.push(&reading(120, 1.0));
+        let aggregate = aggregator.finish().unwrap();
+        assert_eq!(aggregate.end - aggregate.start, chrono::Duration::days(366));
+    }
+}
@@ -0,0 +1,529 @@
+//! Async DHT logger for use inside of a tokio runtime, enabled by the `async` feature.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::stream::{self, Stream};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use super::messages::*;
+use super::parser::FrameParser;
+use super::pipeline::Pipeline;
+use super::sensors::SensorRegistry;
+use super::sinks::{self, Sink, SinkMetrics};
+use super::source::Frames;
+use super::udp::{self, UdpOptions};
+use super::wire::{Encoder, CONTENT_JSON};
+use super::{DhtLoggerConfig, Result, BUFFER_SIZE, TIMEOUT};
+
+/// An async logging channel for DHT sensor measurements. See `Sink` for the synchronous version.
+pub trait AsyncSink: Send {
+    /// Name of the sink as used in the logger config.
+    fn name(&self) -> &str;
+
+    /// Log a single measurement of all DHT sensors.
+    fn log_measurement<'a>(&'a mut self, measurement: &'a DhtSensors) -> BoxFuture<'a, Result<()>>;
+
+    /// Log an aggregate of measurements over a time window.
+    fn log_aggregate<'a>(
+        &'a mut self,
+        aggregate: &'a AggregateSensors,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Flush any data buffered by the sink.
+    fn flush(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Get the metrics of the sink.
+    fn metrics(&self) -> SinkMetrics {
+        SinkMetrics::default()
+    }
+}
+
+/// Run a synchronous sink on the blocking thread pool of the runtime, so that a full buffer or a
+/// slow flush never blocks the runtime.
+pub struct BlockingSink {
+    name: String,
+    sink: Arc<Mutex<Box<dyn Sink>>>,
+}
+
+impl BlockingSink {
+    pub fn new(sink: Box<dyn Sink>) -> BlockingSink {
+        BlockingSink {
+            name: sink.name().to_owned(),
+            sink: Arc::new(Mutex::new(sink)),
+        }
+    }
+
+    async fn run<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Sink) -> Result<()> + Send + 'static,
+    {
+        let sink = self.sink.clone();
+        tokio::task::spawn_blocking(move || f(sink.lock().unwrap().as_mut())).await?
+    }
+}
+
+impl AsyncSink for BlockingSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn log_measurement<'a>(&'a mut self, measurement: &'a DhtSensors) -> BoxFuture<'a, Result<()>> {
+        let measurement = measurement.clone();
+        Box::pin(self.run(move |sink| sink.log_measurement(&measurement)))
+    }
+
+    fn log_aggregate<'a>(
+        &'a mut self,
+        aggregate: &'a AggregateSensors,
+    ) -> BoxFuture<'a, Result<()>> {
+        let aggregate = aggregate.clone();
+        Box::pin(self.run(move |sink| sink.log_aggregate(&aggregate)))
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(|sink| sink.flush()))
+    }
+
+    fn metrics(&self) -> SinkMetrics {
+        self.sink.lock().unwrap().metrics()
+    }
+}
+
+/// Send data to a list of UDP addresses using a tokio socket. The data is the same as sent by
+/// `UdpSink`, but destinations must be given by address.
+pub struct AsyncUdpSink {
+    addrs: Vec<SocketAddr>,
+    socket: UdpSocket,
+    encoder: Encoder,
+}
+
+impl AsyncUdpSink {
+    pub async fn new<A: Into<SocketAddr>>(addrs: Vec<A>) -> Result<AsyncUdpSink> {
+        AsyncUdpSink::with_options(addrs, &UdpOptions::default()).await
+    }
+
+    /// Create a UDP sink sending from a socket with the given options, such as a multicast TTL.
+    pub async fn with_options<A: Into<SocketAddr>>(
+        addrs: Vec<A>,
+        options: &UdpOptions,
+    ) -> Result<AsyncUdpSink> {
+        let socket = options.socket()?;
+        let addrs = addrs
+            .into_iter()
+            .map(|addr| udp::target(&socket, addr.into()))
+            .collect::<Result<_>>()?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        Ok(AsyncUdpSink {
+            addrs,
+            socket,
+            encoder: Encoder::default(),
+        })
+    }
+
+    /// Use an encoder for a configured wire format instead of the default one.
+    pub fn with_encoder(mut self, encoder: Encoder) -> AsyncUdpSink {
+        self.encoder = encoder;
+        self
+    }
+
+    /// Send data to every destination. As with `UdpSink`, a destination that cannot be reached
+    /// is logged and does not keep the data from the others, and an error is only returned if no
+    /// destination could be sent to.
+    async fn send(&self, data: Vec<u8>) -> Result<()> {
+        if data.first() == Some(&CONTENT_JSON) {
+            log::trace!("{}", String::from_utf8_lossy(&data));
+        }
+        let mut sent_any = false;
+        let mut last_error = None;
+        for addr in self.addrs.iter() {
+            match self.socket.send_to(&data, addr).await {
+                Ok(bytes_sent) => {
+                    log::trace!("Sent {} bytes to UDP addr: {:?}", bytes_sent, addr);
+                    sent_any = true;
+                }
+                Err(err) => {
+                    log::warn!("Failed to send to UDP addr {}: {}", addr, err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match last_error {
+            Some(err) if !sent_any => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsyncSink for AsyncUdpSink {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn log_measurement<'a>(&'a mut self, measurement: &'a DhtSensors) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let data = self.encoder.encode_measurement(measurement)?;
+            self.send(data).await
+        })
+    }
+
+    fn log_aggregate<'a>(
+        &'a mut self,
+        aggregate: &'a AggregateSensors,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let data = self.encoder.encode_aggregate(aggregate)?;
+            self.send(data).await
+        })
+    }
+}
+
+/// Async DHT Logger client.
+///
+/// This reads data from any async reader, such as a `tokio_serial::SerialStream`, and logs it the
+/// same way as `DhtLogger`. The sinks of the logger config run as `BlockingSink`s, and additional
+/// async sinks can be passed on creation.
+pub struct AsyncDhtLogger<R> {
+    reader: R,
+    parser: FrameParser,
+    pipeline: Pipeline,
+    sinks: Vec<Box<dyn AsyncSink>>,
+    frames: Frames,
+    timeout: Duration,
+}
+
+impl AsyncDhtLogger<SerialStream> {
+    /// Create an async DHT logger from a DhtLoggerConfig. This must be called from within a tokio
+    /// runtime. Only local serial ports are supported; network sources can be read by passing a
+    /// `tokio::net::TcpStream` to `new`.
+    pub fn from_config(
+        config: &DhtLoggerConfig,
+        sinks: Vec<Box<dyn AsyncSink>>,
+    ) -> AsyncDhtLogger<SerialStream> {
+        let path = config.port.to_str().unwrap();
+        let mut port = config
+            .serial
+            .builder(path, config.baud)
+            .and_then(|builder| Ok(builder.open_native_async()?))
+            .unwrap_or_else(|_| panic!("Failed to open port: {}", path));
+        config
+            .serial
+            .set_control_lines(&mut port)
+            .unwrap_or_else(|err| panic!("Failed to set control lines of {}: {}", path, err));
+
+        AsyncDhtLogger::new(port, config.logger_config.to_owned(), sinks)
+            .with_timeout(config.serial.timeout())
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> AsyncDhtLogger<R> {
+    /// Create an async DHT logger. Panics if the logger config is invalid or a sink cannot be
+    /// opened, see `try_new`.
+    ///
+    /// Args:
+    /// * `reader`: An async reader producing data in the format of the serial interface.
+    /// * `logger_config`: Configure how data is logged. See the `DhtLoggerConfig` documentation.
+    /// * `sinks`: Async sinks to log data to, in addition to the sinks of the logger config.
+    pub fn new(
+        reader: R,
+        logger_config: HashMap<String, Value>,
+        sinks: Vec<Box<dyn AsyncSink>>,
+    ) -> AsyncDhtLogger<R> {
+        AsyncDhtLogger::try_new(reader, logger_config, sinks)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Create an async DHT logger, returning an `InvalidData` error if the logger config is
+    /// invalid, or the error of opening a sink.
+    pub fn try_new(
+        reader: R,
+        logger_config: HashMap<String, Value>,
+        sinks: Vec<Box<dyn AsyncSink>>,
+    ) -> Result<AsyncDhtLogger<R>> {
+        let registry = Arc::new(SensorRegistry::from_logger_config(&logger_config)?);
+        let sinks: Vec<Box<dyn AsyncSink>> = sinks::from_logger_config(&logger_config, &registry)?
+            .into_iter()
+            .map(|sink| Box::new(BlockingSink::new(sink)) as Box<dyn AsyncSink>)
+            .chain(sinks)
+            .collect();
+        let parser = FrameParser::from_logger_config(&logger_config, registry)?;
+        let names: Vec<&str> = sinks.iter().map(|sink| sink.name()).collect();
+        let pipeline = Pipeline::from_logger_config(&logger_config, &names)?;
+
+        Ok(AsyncDhtLogger {
+            reader,
+            parser,
+            pipeline,
+            sinks,
+            frames: Frames::default(),
+            timeout: TIMEOUT,
+        })
+    }
+
+    /// Set the timeout of reading sensor data.
+    pub fn with_timeout(mut self, timeout: Duration) -> AsyncDhtLogger<R> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Read sensor data and return it. Temperatures are returned in degrees Celsius. Returns an
+    /// `UnexpectedEof` error once the reader is closed, and a `TimedOut` error if no data is
+    /// readable within the timeout of the serial port.
+    ///
+    /// Reads of a stream may return part of a frame or several frames, so the data is split into
+    /// frames the way network sources do (see `Frames`).
+    pub async fn read_sensor(&mut self) -> Result<DhtSensors> {
+        let frame = self.read_frame().await?;
+        self.parser.parse(&frame, Utc::now())
+    }
+
+    async fn read_frame(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(frame) = self.frames.next_frame()? {
+                return Ok(frame);
+            }
+
+            let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+            let n_bytes = tokio::time::timeout(self.timeout, self.reader.read(&mut buffer))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out reading DHT sensors"))??;
+            if n_bytes == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "DHT sensor reader closed",
+                ));
+            }
+            self.frames.push(&buffer[..n_bytes]);
+        }
+    }
+
+    /// Get a stream of sensor data, ending when the reader is closed. Errors reading the sensors
+    /// are part of the stream, and the data is not logged.
+    pub fn readings(&mut self) -> impl Stream<Item = Result<DhtSensors>> + '_ {
+        stream::unfold(self, |logger| async move {
+            match logger.read_sensor().await {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => None,
+                result => Some((result, logger)),
+            }
+        })
+    }
+
+    /// Log a measurement to all sinks. See `DhtLogger::log_measurement`.
+    pub async fn log_measurement(&mut self, measurement: DhtSensors) -> Result<()> {
+        let (measurement, aggregate) = self.pipeline.process(measurement);
+
+        let mut result = Ok(());
+        for sink in self.sinks.iter_mut() {
+            let logged = if self.pipeline.aggregates(sink.name()) {
+                match &aggregate {
+                    Some(aggregate) => sink.log_aggregate(aggregate).await,
+                    None => Ok(()),
+                }
+            } else {
+                sink.log_measurement(&measurement).await
+            };
+
+            if let Err(err) = logged {
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+
+    /// Flush all sinks. See `DhtLogger::flush`.
+    pub async fn flush(&mut self) -> Result<()> {
+        let aggregate = self.pipeline.finish();
+
+        let mut result = Ok(());
+        for sink in self.sinks.iter_mut() {
+            if let Some(aggregate) = &aggregate {
+                if self.pipeline.aggregates(sink.name()) {
+                    if let Err(err) = sink.log_aggregate(aggregate).await {
+                        result = result.and(Err(err));
+                    }
+                }
+            }
+
+            if let Err(err) = sink.flush().await {
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+
+    /// Get the metrics of every sink, keyed by sink name.
+    pub fn sink_metrics(&self) -> HashMap<String, SinkMetrics> {
+        self.sinks
+            .iter()
+            .map(|sink| (sink.name().to_owned(), sink.metrics()))
+            .collect()
+    }
+
+    /// Get the registry of sensor metadata.
+    pub fn sensors(&self) -> &SensorRegistry {
+        self.parser.registry()
+    }
+
+    /// Get the number of readings rejected by the filters for each sensor.
+    pub fn rejected_readings(&self) -> HashMap<String, u64> {
+        self.pipeline.rejected()
+    }
+
+    /// Read data from the reader and log it to all sinks until the reader is closed.
+    pub async fn run(&mut self) {
+        loop {
+            let measurement = match self.read_sensor().await {
+                Ok(measurement) => measurement,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => {
+                    log::trace!("{}", err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = self.log_measurement(measurement).await {
+                log::warn!("{}", err);
+            }
+        }
+
+        if let Err(err) = self.flush().await {
+            log::warn!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    struct MemorySink {
+        received: Arc<Mutex<Vec<DhtSensors>>>,
+    }
+
+    impl AsyncSink for MemorySink {
+        fn name(&self) -> &str {
+            "memory"
+        }
+
+        fn log_measurement<'a>(
+            &'a mut self,
+            measurement: &'a DhtSensors,
+        ) -> BoxFuture<'a, Result<()>> {
+            self.received.lock().unwrap().push(measurement.clone());
+            Box::pin(async { Ok(()) })
+        }
+
+        fn log_aggregate<'a>(&'a mut self, _: &'a AggregateSensors) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    // Test streaming readings from an async reader until it is closed
+    #[test]
+    fn test_readings() {
+        block_on(async {
+            let (reader, mut writer) = tokio::io::duplex(BUFFER_SIZE);
+            let mut logger = AsyncDhtLogger::new(reader, HashMap::new(), Vec::new());
+            let mut readings = Box::pin(logger.readings());
+
+            // Two frames written back to back are read together, and the third one is split
+            // across writes.
+            let frame = br#"{"0": {"t": 20.0, "h": 50.0, "hi": 19.6}}"#;
+            let mut data = [&frame[..], b"\n", &frame[..], &frame[..10]].concat();
+            writer.write_all(&data).await.unwrap();
+            for _ in 0..2 {
+                let measurement = readings.next().await.unwrap().unwrap();
+                assert_eq!(measurement.data["0"].temperature, 20.0);
+            }
+
+            data = [&frame[10..], b"[]"].concat();
+            writer.write_all(&data).await.unwrap();
+            let measurement = readings.next().await.unwrap().unwrap();
+            assert_eq!(measurement.data["0"].temperature, 20.0);
+            let err = readings.next().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            drop(writer);
+            assert!(readings.next().await.is_none());
+        });
+    }
+
+    // Test that the async logger logs to async sinks and flushes the configured sinks
+    #[test]
+    fn test_async_sinks() {
+        block_on(async {
+            let (reader, mut writer) = tokio::io::duplex(BUFFER_SIZE);
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let sink = MemorySink {
+                received: received.clone(),
+            };
+            let mut logger = AsyncDhtLogger::new(reader, HashMap::new(), vec![Box::new(sink)]);
+
+            writer
+                .write_all(br#"{"0": {"t": 20.0, "h": 50.0, "hi": 19.6}}"#)
+                .await
+                .unwrap();
+            drop(writer);
+            logger.run().await;
+
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].data["0"].humidity, 50.0);
+            let metrics = logger.sink_metrics();
+            assert_eq!(metrics["log"].sent, 1);
+            assert_eq!(metrics["memory"].sent, 0);
+        });
+    }
+
+    // Test that an unreachable destination does not keep data from the others, and that sending
+    // only fails if no destination is reachable
+    #[test]
+    fn test_udp_unreachable() {
+        block_on(async {
+            let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            // Sending to port 0 always fails.
+            let unreachable = SocketAddr::from(([127, 0, 0, 1], 0));
+            let measurement = DhtSensors {
+                timestamp: Utc::now(),
+                unit: Default::default(),
+                data: Default::default(),
+                errors: Default::default(),
+            };
+
+            let addrs = vec![unreachable, listener.local_addr().unwrap()];
+            let mut sink = AsyncUdpSink::new(addrs).await.unwrap();
+            sink.log_measurement(&measurement).await.unwrap();
+            let mut buffer = [0; BUFFER_SIZE];
+            let received = tokio::time::timeout(TIMEOUT, listener.recv(&mut buffer)).await;
+            assert!(received.unwrap().unwrap() > 0);
+
+            let mut sink = AsyncUdpSink::new(vec![unreachable]).await.unwrap();
+            assert!(sink.log_measurement(&measurement).await.is_err());
+        });
+    }
+}
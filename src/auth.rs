@@ -0,0 +1,357 @@
+//! Authentication of UDP payloads with HMAC-SHA256.
+//!
+//! A signed payload wraps a payload of any encoding (see the `wire` module):
+//! * byte 0: `CONTENT_SIGNED`
+//! * bytes 1..9: Timestamp of signing, in milliseconds since the UNIX epoch, big endian.
+//! * bytes 9..25: Nonce, unique for every payload of a logger.
+//! * bytes 25..57: HMAC-SHA256 tag over bytes 0..25 and the wrapped payload.
+//! * bytes 57..: The wrapped payload, starting with its own content type.
+//!
+//! Listeners reject payloads with an invalid tag, payloads signed more than `max_age` seconds
+//! away from their own clock, and payloads with a nonce they have already seen within that time.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::config::ENV_PREFIX;
+use super::Result;
+
+/// Content type of signed payloads.
+pub const CONTENT_SIGNED: u8 = 0x03;
+/// Minimum length of a key in bytes.
+pub const MIN_KEY_LEN: usize = 16;
+
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+const HEADER_LEN: usize = 1 + 8 + NONCE_LEN;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn default_max_age() -> u64 {
+    30
+}
+
+/// Configuration of payload signing, with the shared key read from a file or an environment
+/// variable. Surrounding whitespace of the key is ignored.
+///
+/// Example configuration YAML (inside of the `wire` section of `logger_config`):
+/// ```yaml
+/// auth:
+///   key_file: /etc/dht-logger/udp.key
+///   # or, from a variable outside of the DHT_LOGGER_ prefix of config overrides
+///   key_env: DHT_UDP_KEY
+///   # Maximum age of a payload in seconds.
+///   max_age: 30
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuthConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_env: Option<String>,
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+impl AuthConfig {
+    /// Check that exactly one source of the key is set and that the key can be loaded, returning
+    /// an `InvalidData` error otherwise. `key_env` must not start with the prefix of config
+    /// overrides, which would copy the key into the logger config.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(Error::new(ErrorKind::InvalidData, reason));
+        match (&self.key_file, &self.key_env) {
+            (Some(_), Some(_)) => {
+                return invalid(String::from(
+                    "wire.auth needs only one of key_file and key_env",
+                ))
+            }
+            (None, None) => {
+                return invalid(String::from("wire.auth needs one of key_file and key_env"))
+            }
+            (None, Some(name)) if name.starts_with(ENV_PREFIX) => {
+                return invalid(format!(
+                    "wire.auth.key_env must not start with {}, got value: {}",
+                    ENV_PREFIX, name
+                ))
+            }
+            _ => (),
+        }
+        if self.max_age == 0 {
+            return invalid(String::from("wire.auth.max_age must be at least 1"));
+        }
+        self.key()?;
+        Ok(())
+    }
+
+    /// Load the key, returning an `InvalidData` error if it cannot be read or is too short.
+    pub fn key(&self) -> Result<Vec<u8>> {
+        self.load_key(|name| env::var(name).ok())
+    }
+
+    /// Load the key, reading environment variables with `var`.
+    fn load_key<F: FnOnce(&str) -> Option<String>>(&self, var: F) -> Result<Vec<u8>> {
+        let key = match (&self.key_file, &self.key_env) {
+            (Some(path), _) => fs::read(path).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to read key from {}: {}", path.display(), err),
+                )
+            })?,
+            (None, Some(name)) => var(name)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Failed to read key from {}: not set", name),
+                    )
+                })?
+                .into_bytes(),
+            (None, None) => return Err(Error::new(ErrorKind::InvalidData, "no key configured")),
+        };
+
+        let key = trim(&key).to_vec();
+        if key.len() < MIN_KEY_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("key must be at least {} bytes long", MIN_KEY_LEN),
+            ));
+        }
+        Ok(key)
+    }
+
+    /// Get the maximum age of a payload.
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age)
+    }
+}
+
+/// Remove surrounding ASCII whitespace, such as the newline at the end of a key file.
+fn trim(key: &[u8]) -> &[u8] {
+    let start = key
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(key.len());
+    let end = key
+        .iter()
+        .rposition(|byte| !byte.is_ascii_whitespace())
+        .map_or(start, |end| end + 1);
+    &key[start..end]
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn mac(key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size")
+}
+
+/// Sign payloads with a shared key.
+pub struct Signer {
+    key: Vec<u8>,
+    prefix: [u8; 8],
+    counter: u64,
+}
+
+impl Signer {
+    pub fn new(key: &[u8]) -> Signer {
+        // The nonce is a random prefix for every signer with a counter, which is unique without
+        // depending on a random number generator.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(now_ms());
+        Signer {
+            key: key.to_vec(),
+            prefix: hasher.finish().to_be_bytes(),
+            counter: 0,
+        }
+    }
+
+    /// Wrap a payload in a signed payload.
+    pub fn sign(&mut self, payload: &[u8]) -> Vec<u8> {
+        self.sign_at(payload, now_ms())
+    }
+
+    fn sign_at(&mut self, payload: &[u8], timestamp: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + TAG_LEN + payload.len());
+        data.push(CONTENT_SIGNED);
+        data.extend(timestamp.to_be_bytes());
+        data.extend(self.prefix);
+        data.extend(self.counter.to_be_bytes());
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut mac = mac(&self.key);
+        mac.update(&data);
+        mac.update(payload);
+        data.extend(mac.finalize().into_bytes());
+        data.extend(payload);
+        data
+    }
+}
+
+/// Verify signed payloads, rejecting replays.
+pub struct Verifier {
+    key: Vec<u8>,
+    max_age: u64,
+    seen: HashMap<[u8; NONCE_LEN], u64>,
+}
+
+impl Verifier {
+    pub fn new(key: &[u8], max_age: Duration) -> Verifier {
+        Verifier {
+            key: key.to_vec(),
+            max_age: max_age.as_millis() as u64,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Verify a signed payload and return the payload it wraps. Returns an `InvalidData` error
+    /// for malformed payloads, and a `PermissionDenied` error for payloads that fail to verify,
+    /// are too old or were already received.
+    pub fn verify<'a>(&mut self, data: &'a [u8]) -> Result<&'a [u8]> {
+        self.verify_at(data, now_ms())
+    }
+
+    fn verify_at<'a>(&mut self, data: &'a [u8], now: u64) -> Result<&'a [u8]> {
+        if data.len() < HEADER_LEN + TAG_LEN || data[0] != CONTENT_SIGNED {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "malformed signed payload",
+            ));
+        }
+        let denied = |reason: &str| Err(Error::new(ErrorKind::PermissionDenied, reason));
+
+        let (header, rest) = data.split_at(HEADER_LEN);
+        let (tag, payload) = rest.split_at(TAG_LEN);
+        let mut mac = mac(&self.key);
+        mac.update(header);
+        mac.update(payload);
+        if mac.verify_slice(tag).is_err() {
+            return denied("invalid payload signature");
+        }
+
+        let timestamp = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        if timestamp.abs_diff(now) > self.max_age {
+            return denied("payload signed outside of the maximum age");
+        }
+
+        let max_age = self.max_age;
+        self.seen
+            .retain(|_, seen| seen.saturating_add(max_age) >= now);
+        let nonce: [u8; NONCE_LEN] = header[9..].try_into().unwrap();
+        if self.seen.insert(nonce, timestamp).is_some() {
+            return denied("replayed payload");
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that signed payloads verify once, and that tampered, stale and replayed payloads are
+    // rejected
+    #[test]
+    fn test_sign_verify() {
+        let key = b"0123456789abcdef";
+        let mut signer = Signer::new(key);
+        let mut verifier = Verifier::new(key, Duration::from_secs(30));
+
+        let data = signer.sign_at(b"{}", 1_000_000);
+        assert_eq!(verifier.verify_at(&data, 1_010_000).unwrap(), b"{}");
+        let denied =
+            |result: Result<&[u8]>| result.unwrap_err().kind() == ErrorKind::PermissionDenied;
+        assert!(denied(verifier.verify_at(&data, 1_010_000)));
+
+        let mut tampered = signer.sign_at(b"{}", 1_000_000);
+        *tampered.last_mut().unwrap() = b']';
+        assert!(denied(verifier.verify_at(&tampered, 1_000_000)));
+
+        let stale = signer.sign_at(b"{}", 1_000_000);
+        assert!(denied(verifier.verify_at(&stale, 1_031_000)));
+
+        let other = Signer::new(b"fedcba9876543210").sign_at(b"{}", 1_000_000);
+        assert!(denied(verifier.verify_at(&other, 1_000_000)));
+        assert_eq!(
+            verifier
+                .verify_at(&data[..40], 1_000_000)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+
+        // Nonces are forgotten once their payloads are too old to be accepted anyway.
+        let fresh = signer.sign_at(b"{}", 2_000_000);
+        assert!(verifier.verify_at(&fresh, 2_000_000).is_ok());
+        assert_eq!(verifier.seen.len(), 1);
+    }
+
+    // Test that keys are read from the environment or a file, trimmed, and rejected if short
+    #[test]
+    fn test_auth_config() {
+        let var = |name: &str| match name {
+            "DHT_UDP_KEY" => Some(String::from(" 0123456789abcdef\n")),
+            "DHT_UDP_SHORT_KEY" => Some(String::from("secret")),
+            _ => None,
+        };
+        let config: AuthConfig = serde_yaml::from_str("key_env: DHT_UDP_KEY").unwrap();
+        assert_eq!(config.load_key(var).unwrap(), b"0123456789abcdef");
+        assert_eq!(config.max_age(), Duration::from_secs(30));
+        for name in ["DHT_UDP_SHORT_KEY", "DHT_UDP_MISSING_KEY"] {
+            let config = AuthConfig {
+                key_env: Some(String::from(name)),
+                ..config.clone()
+            };
+            let err = config.load_key(var).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        let path = env::temp_dir().join(format!("dht-logger-{}.key", std::process::id()));
+        let config = AuthConfig {
+            key_file: Some(path.clone()),
+            key_env: None,
+            max_age: 30,
+        };
+        fs::write(&path, "0123456789abcdef\n").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.key().unwrap(), b"0123456789abcdef");
+        fs::write(&path, "secret\n").unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let invalid = [
+            "key_env: DHT_LOGGER_UDP_KEY",
+            "{key_env: DHT_UDP_KEY, key_file: /etc/key}",
+            "{key_file: /etc/key, max_age: 0}",
+            "max_age: 30",
+        ];
+        for invalid in invalid {
+            let config: AuthConfig = serde_yaml::from_str(invalid).unwrap();
+            assert_eq!(
+                config.validate().unwrap_err().kind(),
+                ErrorKind::InvalidData,
+                "{}",
+                invalid
+            );
+        }
+    }
+}
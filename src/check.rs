@@ -0,0 +1,139 @@
+//! Dry run of a DHT logger config against a device, without logging any data.
+
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use super::aggregate::AggregateConfig;
+use super::config::DhtLoggerConfig;
+use super::messages::*;
+use super::parser::FrameParser;
+use super::sensors::SensorRegistry;
+use super::sinks::SinksConfig;
+use super::source::Source;
+use super::{Result, BUFFER_SIZE};
+
+/// What a sink would receive from the logger.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Every measurement.
+    Measurements,
+    /// Aggregates over windows of the given number of seconds.
+    Aggregates(u64),
+}
+
+/// Result of reading a frame with a config.
+#[derive(Debug)]
+pub struct CheckReport {
+    /// The parsed frame, in degrees Celsius.
+    pub measurement: DhtSensors,
+    /// Every configured sink and what it would receive.
+    pub sinks: Vec<(String, Route)>,
+}
+
+impl CheckReport {
+    /// Check if every sensor in the frame returned a valid reading.
+    pub fn is_ok(&self) -> bool {
+        self.measurement.errors.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut labels: Vec<&String> = self.measurement.data.keys().collect();
+        labels.sort();
+        writeln!(f, "Sensors:")?;
+        for label in labels {
+            let data = &self.measurement.data[label];
+            write!(
+                f,
+                "  {}: temperature {} °C, humidity {} %, heat index {} °C",
+                label, data.temperature, data.humidity, data.heat_index
+            )?;
+            for (name, value) in data.extra.iter() {
+                write!(f, ", {} {}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+
+        let mut labels: Vec<&String> = self.measurement.errors.keys().collect();
+        labels.sort();
+        writeln!(f, "Errors:")?;
+        for label in labels {
+            writeln!(f, "  {}: {}", label, self.measurement.errors[label])?;
+        }
+
+        writeln!(f, "Sinks:")?;
+        for (name, route) in self.sinks.iter() {
+            match route {
+                Route::Measurements => writeln!(f, "  {}: every measurement", name)?,
+                Route::Aggregates(interval) => {
+                    writeln!(f, "  {}: aggregates over {} s", name, interval)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get every sink configured in a logger config and what it would receive, returning an
+/// `InvalidData` error if the sinks of the config are invalid (see `DhtLoggerConfig::validate`).
+pub fn routes(config: &DhtLoggerConfig) -> Result<Vec<(String, Route)>> {
+    let aggregate: Option<AggregateConfig> = config
+        .logger_config
+        .get("aggregate")
+        .and_then(|aggregate| serde_json::from_value(aggregate.clone()).ok());
+
+    let names = SinksConfig::from_logger_config(&config.logger_config)?.names();
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            let route = match &aggregate {
+                Some(aggregate) if aggregate.sinks.iter().any(|sink| sink == name) => {
+                    Route::Aggregates(aggregate.interval)
+                }
+                _ => Route::Measurements,
+            };
+            (name.to_owned(), route)
+        })
+        .collect())
+}
+
+/// Read a single frame from a source and parse it with the sensor metadata and input unit of
+/// the config. Reading is retried up to `retries` times, after which the last error is returned.
+/// The config must be valid (see `DhtLoggerConfig::validate`).
+pub fn read_frame(
+    config: &DhtLoggerConfig,
+    mut source: Box<dyn Source>,
+    retries: u32,
+) -> Result<CheckReport> {
+    let registry = Arc::new(SensorRegistry::from_logger_config(&config.logger_config)?);
+    let parser = FrameParser::from_logger_config(&config.logger_config, registry)?;
+
+    let mut retry = 0;
+    let measurement = loop {
+        let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        let result = source
+            .read(&mut buffer)
+            .and_then(|n_bytes| parser.parse(&buffer[..n_bytes], Utc::now()));
+        match result {
+            Ok(measurement) => break measurement,
+            Err(err) => {
+                retry += 1;
+                log::trace!("{}", err);
+                if retry >= retries {
+                    return Err(err);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    };
+
+    Ok(CheckReport {
+        measurement,
+        sinks: routes(config)?,
+    })
+}
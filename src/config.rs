@@ -0,0 +1,633 @@
+//! Configuration of a DHT logger, loaded from a file and overridden by the environment or the
+//! command line.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::aggregate::AggregateConfig;
+use super::filters::FiltersConfig;
+use super::gpio::{self, GpioConfig};
+use super::parser::FrameParser;
+use super::pipeline::Pipeline;
+use super::sensors::{SensorMetadata, SensorRegistry, UnknownSensors};
+use super::serial::SerialConfig;
+use super::sinks::{SinkOptions, SinksConfig};
+use super::source::{Source, SourceAddr};
+use super::udp::UdpOptions;
+use super::units::TemperatureUnit;
+use super::wire::WireConfig;
+use super::Result;
+
+/// Baud rate used if neither the config file nor an override sets one.
+pub const DEFAULT_BAUD: u32 = 115200;
+
+/// Prefix of environment variables overriding config values.
+pub const ENV_PREFIX: &str = "DHT_LOGGER_";
+
+/// Options of the `logger_config` that can be overridden by environment variables.
+pub const LOGGER_OPTIONS: [&str; 11] = [
+    "verbose",
+    "udp",
+    "csv",
+    "input_unit",
+    "sensors",
+    "unknown_sensors",
+    "sinks",
+    "filters",
+    "aggregate",
+    "wire",
+    "udp_options",
+];
+
+/// Configuration of a DHT Logger client.
+///
+/// Example configuration YAML:
+/// ```yaml
+/// # Serial port configuration
+/// # Serial port, or tcp://host:port for a raw TCP
+/// # stream, rfc2217://host:port for a serial port
+/// # over telnet or gpio:///dev/gpiochipN for
+/// # sensors on GPIO lines (see `SourceAddr`)
+/// port: /dev/ttyUSB0
+/// baud: 115200
+/// # Serial line settings, defaulting to 8N1
+/// # (see `SerialConfig`)
+/// serial:
+///   flow_control: hardware
+///   # Do not reset the Arduino on open
+///   dtr: false
+///
+/// # Configure how the sensor data is logged.
+/// logger_config:
+///   # verbose: true tells the logger to
+///   # use log::info! for sensor readings
+///   verbose: true
+///   # Send compact JSON to UDP addresses,
+///   # IPv4, IPv6 or hostnames
+///   udp:
+///     - 127.0.0.1:9898
+///     - "[fd00::10]:9898"
+///     - dashboard.lan:9898
+///   # Wire format of the UDP data
+///   # (see `WireConfig`)
+///   wire:
+///     source_id: greenhouse
+///     encoding: cbor
+///     # Sign payloads (see `AuthConfig`)
+///     auth:
+///       key_env: DHT_UDP_KEY
+///   # Socket of the UDP data, for multicast
+///   # and broadcast (see `UdpOptions`)
+///   udp_options:
+///     interface: eth0
+///     multicast_ttl: 1
+///     resolve_interval: 300
+///   # Append readings to a CSV file
+///   csv: /var/log/dht-logger.csv
+///   # Temperature unit sent by the device
+///   input_unit: celsius
+///   # Metadata of each sensor label
+///   sensors:
+///     sensor_label:
+///       name: Living room
+///       location: downstairs
+///       model: DHT22
+///       tags:
+///         floor: "1"
+///   # Log a warning for sensors not in `sensors`
+///   unknown_sensors: log
+///   # Options for each sink
+///   sinks:
+///     udp:
+///       units: fahrenheit
+///       # Buffer data on disk while sending fails
+///       queue:
+///         path: /var/lib/dht-logger/udp.queue
+///         max_bytes: 16777216
+///       # Buffer of the sink worker thread
+///       dispatch:
+///         capacity: 64
+///         overflow: drop_oldest
+///   # Reject humidity readings outside of
+///   # [0, 100] for all sensors
+///   filters:
+///     sensors:
+///       "*":
+///         - type: range
+///           humidity: {min: 0, max: 100}
+///   # Send 1-minute aggregates instead of
+///   # raw readings to the listed sinks
+///   aggregate:
+///     interval: 60
+///     sinks:
+///       - udp
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DhtLoggerConfig {
+    pub port: PathBuf,
+    pub baud: u32,
+    #[serde(default)]
+    pub serial: SerialConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpio: Option<GpioConfig>,
+    pub logger_config: HashMap<String, Value>,
+}
+
+impl DhtLoggerConfig {
+    /// Load a YAML config file into a config struct
+    pub fn load_yaml(config_file: &Path) -> DhtLoggerConfig {
+        match DhtLoggerConfig::read_yaml(config_file) {
+            Ok(dht_logger) => dht_logger,
+            Err(_) => panic!("YAML parse error in DHT logger config."),
+        }
+    }
+
+    /// Read a YAML config file into a config struct, returning an error if it is invalid.
+    pub fn read_yaml(config_file: &Path) -> Result<DhtLoggerConfig> {
+        let config_file = File::open(config_file)?;
+        serde_yaml::from_reader(config_file).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    /// Get the address of the source of the config (see `SourceAddr`).
+    pub fn source(&self) -> SourceAddr {
+        SourceAddr::parse(&self.port)
+    }
+
+    /// Open the source of the config.
+    pub fn open_source(&self) -> Result<Box<dyn Source>> {
+        let source = match (self.source(), &self.gpio) {
+            (SourceAddr::Gpio(chip), Some(config)) => gpio::open(&chip, config)?,
+            (addr, _) => addr.open(self.baud, &self.serial)?,
+        };
+        log::trace!("Opened {:?} with {:?}", self.source(), self.serial);
+        Ok(source)
+    }
+
+    /// Load a config file in the format given by its extension (see `ConfigFormat`).
+    pub fn load(config_file: &Path) -> Result<DhtLoggerConfig> {
+        let format = ConfigFormat::from_path(config_file)?;
+        format.parse(&fs::read_to_string(config_file)?)
+    }
+
+    /// Get the effective config, with the default value of every logger config option that has
+    /// one made explicit. Options are parsed and serialized again, so that the result is in the
+    /// same normalized form for every source format.
+    pub fn with_defaults(&self) -> Result<DhtLoggerConfig> {
+        let mut logger_config = self.logger_config.clone();
+        normalize(&mut logger_config, "verbose", Some(false))?;
+        normalize(&mut logger_config, "udp", Some(Vec::<String>::new()))?;
+        normalize(
+            &mut logger_config,
+            "input_unit",
+            Some(TemperatureUnit::default()),
+        )?;
+        normalize(
+            &mut logger_config,
+            "sensors",
+            Some(HashMap::<String, SensorMetadata>::new()),
+        )?;
+        normalize(
+            &mut logger_config,
+            "unknown_sensors",
+            Some(UnknownSensors::default()),
+        )?;
+        normalize(&mut logger_config, "filters", None::<FiltersConfig>)?;
+        normalize(&mut logger_config, "aggregate", None::<AggregateConfig>)?;
+        normalize(&mut logger_config, "wire", Some(WireConfig::default()))?;
+        normalize(
+            &mut logger_config,
+            "udp_options",
+            Some(UdpOptions::default()),
+        )?;
+
+        let mut options: HashMap<String, SinkOptions> =
+            parse_option(logger_config.get("sinks"), "sinks")?.unwrap_or_default();
+        for name in SinksConfig::from_logger_config(&logger_config)?.names() {
+            options.entry(name.to_owned()).or_default();
+        }
+        logger_config.insert(String::from("sinks"), serde_json::to_value(options)?);
+
+        Ok(DhtLoggerConfig {
+            port: self.port.clone(),
+            baud: self.baud,
+            serial: self.serial.clone(),
+            gpio: self.gpio.clone(),
+            logger_config,
+        })
+    }
+
+    /// Validate the config without opening the source or any sink, returning an `InvalidData`
+    /// error describing the first problem found. The logger config is parsed by the same
+    /// constructors that create a DHT logger from it.
+    pub fn validate(&self) -> Result<()> {
+        let config = self.with_defaults()?;
+        let logger_config = &config.logger_config;
+        config.serial.validate()?;
+        if let SourceAddr::Gpio(_) = config.source() {
+            match &config.gpio {
+                Some(gpio) => gpio.validate()?,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "a gpio port needs the gpio section",
+                    ))
+                }
+            }
+        }
+
+        let registry = Arc::new(SensorRegistry::from_logger_config(logger_config)?);
+        FrameParser::from_logger_config(logger_config, registry)?;
+        let sinks = SinksConfig::from_logger_config(logger_config)?;
+        Pipeline::from_logger_config(logger_config, &sinks.names())?;
+        Ok(())
+    }
+
+    /// Resolve every address the logger sends data to.
+    pub fn resolve_addresses(&self) -> Result<Vec<SocketAddr>> {
+        let udp: Vec<String> =
+            parse_option(self.logger_config.get("udp"), "udp")?.unwrap_or_default();
+        let mut addrs = Vec::new();
+        for addr in udp.iter() {
+            let resolved = addr.to_socket_addrs().map_err(|err| {
+                Error::new(err.kind(), format!("Failed to resolve {}: {}", addr, err))
+            })?;
+            addrs.extend(resolved);
+        }
+        Ok(addrs)
+    }
+}
+
+fn parse_option<T: DeserializeOwned>(value: Option<&Value>, name: &str) -> Result<Option<T>> {
+    value
+        .map(|value| {
+            serde_json::from_value(value.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.{}: {}", name, err),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// Parse a logger config option as `T` and serialize it again, setting missing options to
+/// `default` if given.
+fn normalize<T: DeserializeOwned + Serialize>(
+    logger_config: &mut HashMap<String, Value>,
+    name: &str,
+    default: Option<T>,
+) -> Result<()> {
+    let value = parse_option(logger_config.get(name), name)?.or(default);
+    if let Some(value) = value {
+        logger_config.insert(name.to_owned(), serde_json::to_value(value)?);
+    }
+    Ok(())
+}
+
+/// Format of a config file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detect the format of a config file from its extension: `.yaml` or `.yml`, `.toml` and
+    /// `.json`.
+    pub fn from_path(path: &Path) -> Result<ConfigFormat> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        extension.unwrap_or_default().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown config file format: {}", path.display()),
+            )
+        })
+    }
+
+    /// Parse a config in this format.
+    pub fn parse(&self, config: &str) -> Result<DhtLoggerConfig> {
+        let invalid = |err: &dyn fmt::Display| Error::new(ErrorKind::InvalidData, err.to_string());
+        match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(config).map_err(|err| invalid(&err)),
+            ConfigFormat::Toml => toml::from_str(config).map_err(|err| invalid(&err)),
+            ConfigFormat::Json => serde_json::from_str(config).map_err(|err| invalid(&err)),
+        }
+    }
+
+    /// Serialize a config in this format, with options in sorted order. TOML has no null values,
+    /// so unset options are left out of TOML.
+    pub fn to_string(&self, config: &DhtLoggerConfig) -> Result<String> {
+        let invalid = |err: &dyn fmt::Display| Error::new(ErrorKind::InvalidData, err.to_string());
+        let config = serde_json::to_value(config)?;
+        match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(&config).map_err(|err| invalid(&err)),
+            ConfigFormat::Toml => {
+                let config = without_nulls(config);
+                let config = toml::Value::try_from(config).map_err(|err| invalid(&err))?;
+                toml::to_string_pretty(&config).map_err(|err| invalid(&err))
+            }
+            ConfigFormat::Json => Ok(serde_json::to_string_pretty(&config)?),
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!("unknown config format: {}", format)),
+        }
+    }
+}
+
+/// Remove all null values from maps and lists.
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .filter(|value| !value.is_null())
+                .map(without_nulls)
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// Overrides of config values, applied on top of a config file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigOverrides {
+    pub port: Option<PathBuf>,
+    pub baud: Option<u32>,
+    /// Options of the `logger_config`, replacing the options of the same name.
+    pub logger_config: HashMap<String, Value>,
+}
+
+impl ConfigOverrides {
+    /// Read overrides from the `DHT_LOGGER_*` environment variables.
+    pub fn from_env() -> Result<ConfigOverrides> {
+        ConfigOverrides::from_vars(std::env::vars())
+    }
+
+    /// Read overrides from `DHT_LOGGER_*` variables.
+    ///
+    /// `DHT_LOGGER_PORT` and `DHT_LOGGER_BAUD` set the serial port, and `DHT_LOGGER_UDP` sets a
+    /// comma separated list of UDP addresses. `DHT_LOGGER_<OPTION>` sets the logger config option
+    /// of the same name in lowercase, parsed as a YAML value, for every option in
+    /// `LOGGER_OPTIONS`, for example `DHT_LOGGER_VERBOSE=true` or
+    /// `DHT_LOGGER_AGGREGATE='{interval: 60, sinks: [udp]}'`. Any other `DHT_LOGGER_*` variable
+    /// is an `InvalidInput` error, so that typos and unrelated variables, such as secrets, are
+    /// never copied into the logger config.
+    pub fn from_vars<I>(vars: I) -> Result<ConfigOverrides>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut overrides = ConfigOverrides::default();
+        for (name, value) in vars {
+            let option = match name.strip_prefix(ENV_PREFIX) {
+                Some(option) => option.to_lowercase(),
+                None => continue,
+            };
+
+            let invalid = |err: &dyn std::fmt::Display| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid {}: {}", name, err),
+                )
+            };
+            match option.as_str() {
+                "port" => overrides.port = Some(PathBuf::from(value)),
+                "baud" => overrides.baud = Some(value.parse().map_err(|err| invalid(&err))?),
+                "udp" => overrides.set_udp(value.split(',').map(str::trim)),
+                _ if LOGGER_OPTIONS.contains(&option.as_str()) => {
+                    let value = serde_yaml::from_str(&value).map_err(|err| invalid(&err))?;
+                    overrides.logger_config.insert(option, value);
+                }
+                _ => return Err(invalid(&"not a config option")),
+            }
+        }
+
+        Ok(overrides)
+    }
+
+    /// Replace the list of UDP addresses, ignoring empty addresses.
+    pub fn set_udp<'a, I: IntoIterator<Item = &'a str>>(&mut self, addrs: I) {
+        let addrs = addrs
+            .into_iter()
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| Value::String(addr.to_owned()))
+            .collect();
+        self.logger_config
+            .insert(String::from("udp"), Value::Array(addrs));
+    }
+
+    /// Layer other overrides on top of these ones.
+    pub fn merge(mut self, other: ConfigOverrides) -> ConfigOverrides {
+        self.port = other.port.or(self.port);
+        self.baud = other.baud.or(self.baud);
+        self.logger_config.extend(other.logger_config);
+        self
+    }
+
+    /// Apply the overrides to an optional config file. Without a config file, the overrides must
+    /// set the serial port.
+    pub fn apply(self, config: Option<DhtLoggerConfig>) -> Result<DhtLoggerConfig> {
+        let mut config = match (config, self.port.clone()) {
+            (Some(config), _) => config,
+            (None, Some(port)) => DhtLoggerConfig {
+                port,
+                baud: DEFAULT_BAUD,
+                serial: SerialConfig::default(),
+                gpio: None,
+                logger_config: HashMap::new(),
+            },
+            (None, None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "The serial port must be set without a config file",
+                ))
+            }
+        };
+
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(baud) = self.baud {
+            config.baud = baud;
+        }
+        config.logger_config.extend(self.logger_config);
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // Test that environment variables are parsed and layered on top of a config file
+    #[test]
+    fn test_overrides() {
+        let env = ConfigOverrides::from_vars(vars(&[
+            ("DHT_LOGGER_BAUD", "9600"),
+            ("DHT_LOGGER_UDP", "127.0.0.1:9898, 127.0.0.1:9899"),
+            ("DHT_LOGGER_VERBOSE", "true"),
+            ("DHT_LOGGER_AGGREGATE", "{interval: 60, sinks: [udp]}"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        let mut cli = ConfigOverrides {
+            baud: Some(57600),
+            ..Default::default()
+        };
+        cli.set_udp(["127.0.0.1:1234"]);
+
+        let file: DhtLoggerConfig = serde_yaml::from_str(
+            "{port: /dev/ttyUSB0, baud: 115200, logger_config: {verbose: false, csv: a.csv}}",
+        )
+        .unwrap();
+        let config = env.merge(cli).apply(Some(file)).unwrap();
+        assert_eq!(config.port, PathBuf::from("/dev/ttyUSB0"));
+        assert_eq!(config.baud, 57600);
+        assert_eq!(config.logger_config["verbose"], Value::Bool(true));
+        assert_eq!(config.logger_config["csv"], "a.csv");
+        assert_eq!(
+            config.logger_config["udp"],
+            serde_json::json!(["127.0.0.1:1234"])
+        );
+        assert_eq!(config.logger_config["aggregate"]["interval"], 60);
+
+        assert!(ConfigOverrides::from_vars(vars(&[("DHT_LOGGER_BAUD", "fast")])).is_err());
+        let err = ConfigOverrides::from_vars(vars(&[("DHT_LOGGER_VERBOSSE", "true")])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    // Test that the same config is loaded from every format, and printed with its defaults
+    #[test]
+    fn test_formats() {
+        let yaml = "port: /dev/ttyUSB0\nbaud: 9600\nlogger_config:\n  udp: [127.0.0.1:9898]\n";
+        let toml =
+            "port = \"/dev/ttyUSB0\"\nbaud = 9600\n[logger_config]\nudp = [\"127.0.0.1:9898\"]\n";
+        let json = concat!(
+            r#"{"port": "/dev/ttyUSB0", "baud": 9600, "#,
+            r#""logger_config": {"udp": ["127.0.0.1:9898"]}}"#,
+        );
+
+        let configs: Vec<DhtLoggerConfig> = [
+            (ConfigFormat::Yaml, yaml),
+            (ConfigFormat::Toml, toml),
+            (ConfigFormat::Json, json),
+        ]
+        .iter()
+        .map(|(format, config)| format.parse(config).unwrap())
+        .collect();
+        for config in configs.iter() {
+            assert_eq!(config.port, configs[0].port);
+            assert_eq!(config.baud, configs[0].baud);
+            assert_eq!(config.logger_config, configs[0].logger_config);
+        }
+
+        let config = configs[0].with_defaults().unwrap();
+        assert_eq!(config.logger_config["verbose"], Value::Bool(false));
+        assert_eq!(config.logger_config["input_unit"], "celsius");
+        assert_eq!(config.logger_config["unknown_sensors"], "accept");
+        assert_eq!(
+            config.logger_config["sinks"]["udp"]["dispatch"]["capacity"],
+            64
+        );
+        assert!(config.logger_config["sinks"].get("csv").is_none());
+
+        for format in [ConfigFormat::Yaml, ConfigFormat::Toml, ConfigFormat::Json] {
+            let printed = format.parse(&format.to_string(&config).unwrap()).unwrap();
+            assert_eq!(printed.baud, 9600);
+            assert_eq!(
+                printed.logger_config["sinks"]["log"]["dispatch"]["overflow"],
+                "block"
+            );
+        }
+
+        let path = Path::new("dht-logger.yml");
+        assert_eq!(ConfigFormat::from_path(path).unwrap(), ConfigFormat::Yaml);
+        assert!(ConfigFormat::from_path(Path::new("dht-logger.ini")).is_err());
+    }
+
+    // Test validation of logger configs
+    #[test]
+    fn test_validate() {
+        let config = |logger_config: Value| DhtLoggerConfig {
+            port: PathBuf::from("/dev/ttyUSB0"),
+            baud: DEFAULT_BAUD,
+            serial: SerialConfig::default(),
+            gpio: None,
+            logger_config: serde_json::from_value(logger_config).unwrap(),
+        };
+
+        let valid = config(serde_json::json!({
+            "udp": ["127.0.0.1:9898"],
+            "sinks": {"udp": {"units": "kelvin"}},
+            "aggregate": {"interval": 60, "sinks": ["udp"]},
+        }));
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.resolve_addresses().unwrap().len(), 1);
+
+        for logger_config in [
+            serde_json::json!({"udp": ["localhost"]}),
+            serde_json::json!({"verbose": "yes"}),
+            serde_json::json!({"sinks": {"csv": {}}}),
+            serde_json::json!({"sinks": {"log": {"dispatch": {"capacity": 0}}}}),
+            serde_json::json!({"aggregate": {"interval": 0, "sinks": []}}),
+            serde_json::json!({"aggregate": {"interval": u64::MAX, "sinks": []}}),
+            serde_json::json!({"aggregate": {"interval": 60, "sinks": ["udp"]}}),
+            serde_json::json!({"filters": {"sensors": {"*": [{"type": "unknown"}]}}}),
+            serde_json::json!({"filters": {"sensors": {"*": [{"type": "median", "size": 0}]}}}),
+            serde_json::json!({"filters": {"sensors": {"*": [{"type": "ema", "alpha": 1.5}]}}}),
+            serde_json::json!({"sensors": {"0": {"model": "DHT99"}}}),
+            serde_json::json!({"input_unit": "rankine"}),
+            serde_json::json!({"wire": {"version": 0, "encoding": "cbor"}}),
+        ] {
+            let err = config(logger_config.clone()).validate().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", logger_config);
+        }
+    }
+
+    // Test that the config file is optional if the serial port is overridden
+    #[test]
+    fn test_without_file() {
+        assert!(ConfigOverrides::default().apply(None).is_err());
+
+        let overrides = ConfigOverrides {
+            port: Some(PathBuf::from("/dev/ttyACM0")),
+            ..Default::default()
+        };
+        let config = overrides.apply(None).unwrap();
+        assert_eq!(config.port, PathBuf::from("/dev/ttyACM0"));
+        assert_eq!(config.baud, DEFAULT_BAUD);
+        assert!(config.logger_config.is_empty());
+    }
+}
@@ -0,0 +1,444 @@
+//! Dispatch of data to sinks on background worker threads.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::messages::*;
+use super::sinks::{Sink, SinkMetrics};
+use super::Result;
+
+/// Default number of records buffered for a sink worker.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+fn default_capacity() -> usize {
+    DEFAULT_CAPACITY
+}
+
+/// What to do with new data when the buffer of a sink worker is full.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Block the caller until there is room in the buffer.
+    #[default]
+    Block,
+    /// Drop the new data.
+    DropNewest,
+    /// Drop the oldest buffered data to make room for the new data.
+    DropOldest,
+}
+
+/// Configuration of the worker thread of a sink.
+///
+/// Example configuration YAML (inside of `sinks.<name>`):
+/// ```yaml
+/// dispatch:
+///   capacity: 64
+///   overflow: drop_oldest
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DispatchConfig {
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        DispatchConfig {
+            capacity: DEFAULT_CAPACITY,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl DispatchConfig {
+    /// Check that the capacity is positive, returning an `InvalidData` error otherwise.
+    pub fn validate(&self) -> Result<()> {
+        if self.capacity == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "dispatch capacity must be positive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+enum Job {
+    Measurement(DhtSensors, Instant),
+    Aggregate(AggregateSensors, Instant),
+    Flush(mpsc::Sender<Result<()>>),
+    Stop,
+}
+
+impl Job {
+    fn is_data(&self) -> bool {
+        matches!(self, Job::Measurement(..) | Job::Aggregate(..))
+    }
+}
+
+/// Queued jobs with the number of data jobs among them. The channel is closed once the worker
+/// stops, even if it panics.
+#[derive(Default)]
+struct Jobs {
+    queue: VecDeque<Job>,
+    data: usize,
+    closed: bool,
+}
+
+/// A bounded job queue shared between a `ThreadedSink` and its worker. Only data jobs count
+/// towards the capacity, so that control jobs are never dropped.
+struct Channel {
+    jobs: Mutex<Jobs>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+fn stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "sink worker stopped")
+}
+
+impl Channel {
+    /// Push a control job. Jobs pushed after the worker stopped are dropped, which fails a flush
+    /// waiting for its reply.
+    fn push_control(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if !jobs.closed {
+            jobs.queue.push_back(job);
+            self.not_empty.notify_one();
+        }
+    }
+
+    /// Push a data job, returning whether any data was dropped, or a `BrokenPipe` error if the
+    /// worker stopped.
+    fn push_data(&self, job: Job, config: &DispatchConfig) -> Result<bool> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut dropped = false;
+        if jobs.data >= config.capacity && !jobs.closed {
+            match config.overflow {
+                OverflowPolicy::Block => {
+                    while jobs.data >= config.capacity && !jobs.closed {
+                        jobs = self.not_full.wait(jobs).unwrap();
+                    }
+                }
+                OverflowPolicy::DropNewest => return Ok(true),
+                OverflowPolicy::DropOldest => {
+                    let oldest = jobs.queue.iter().position(|job| job.is_data()).unwrap();
+                    jobs.queue.remove(oldest);
+                    jobs.data -= 1;
+                    dropped = true;
+                }
+            }
+        }
+        if jobs.closed {
+            return Err(stopped());
+        }
+
+        jobs.queue.push_back(job);
+        jobs.data += 1;
+        self.not_empty.notify_one();
+        Ok(dropped)
+    }
+
+    fn pop(&self) -> Job {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = jobs.queue.pop_front() {
+                if job.is_data() {
+                    jobs.data -= 1;
+                }
+                self.not_full.notify_one();
+                return job;
+            }
+            jobs = self.not_empty.wait(jobs).unwrap();
+        }
+    }
+
+    /// Close the channel, dropping all queued jobs and waking up callers blocked on a full
+    /// buffer.
+    fn close(&self) {
+        let mut jobs = self.jobs.lock().unwrap_or_else(PoisonError::into_inner);
+        jobs.closed = true;
+        jobs.queue.clear();
+        jobs.data = 0;
+        self.not_full.notify_all();
+    }
+}
+
+/// Closes a channel when its worker returns or unwinds.
+struct CloseOnExit(Arc<Channel>);
+
+impl Drop for CloseOnExit {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+fn run_worker(mut sink: Box<dyn Sink>, channel: Arc<Channel>, metrics: Arc<Mutex<SinkMetrics>>) {
+    let _close = CloseOnExit(channel.clone());
+    loop {
+        let (result, queued) = match channel.pop() {
+            Job::Measurement(measurement, queued) => (sink.log_measurement(&measurement), queued),
+            Job::Aggregate(aggregate, queued) => (sink.log_aggregate(&aggregate), queued),
+            Job::Flush(done) => {
+                let _ = done.send(sink.flush());
+                let mut metrics = metrics.lock().unwrap();
+                *metrics = merge(sink.metrics(), &metrics);
+                continue;
+            }
+            Job::Stop => return,
+        };
+
+        let latency = queued.elapsed();
+        if let Err(err) = &result {
+            log::warn!(
+                sink = sink.name(), error_kind = "sink";
+                "Failed to log to '{}' sink: {}", sink.name(), err
+            );
+        }
+
+        let mut metrics = metrics.lock().unwrap();
+        *metrics = merge(sink.metrics(), &metrics);
+        metrics.sent += 1;
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        metrics.last_latency = Some(latency);
+        metrics.max_latency = metrics.max_latency.max(Some(latency));
+    }
+}
+
+/// Combine the metrics of a sink with the dispatch metrics of its worker.
+fn merge(sink: SinkMetrics, dispatch: &SinkMetrics) -> SinkMetrics {
+    SinkMetrics {
+        sent: dispatch.sent,
+        errors: dispatch.errors,
+        dropped: dispatch.dropped,
+        last_latency: dispatch.last_latency,
+        max_latency: dispatch.max_latency,
+        ..sink
+    }
+}
+
+/// Run a sink on its own worker thread, fed by a bounded buffer.
+///
+/// Logging data only blocks if the buffer is full and the overflow policy is `block`. Errors of
+/// the sink are logged by the worker, and flushing waits until all buffered data is processed.
+/// If the worker panics, buffered data is lost and logging or flushing returns a `BrokenPipe`
+/// error.
+pub struct ThreadedSink {
+    name: String,
+    config: DispatchConfig,
+    channel: Arc<Channel>,
+    metrics: Arc<Mutex<SinkMetrics>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl ThreadedSink {
+    pub fn new(sink: Box<dyn Sink>, config: DispatchConfig) -> Result<ThreadedSink> {
+        config.validate()?;
+
+        let name = sink.name().to_owned();
+        let channel = Arc::new(Channel {
+            jobs: Mutex::new(Jobs::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        });
+        let metrics = Arc::new(Mutex::new(sink.metrics()));
+
+        let worker = {
+            let channel = channel.clone();
+            let metrics = metrics.clone();
+            thread::Builder::new()
+                .name(format!("sink-{}", name))
+                .spawn(move || run_worker(sink, channel, metrics))?
+        };
+
+        Ok(ThreadedSink {
+            name,
+            config,
+            channel,
+            metrics,
+            worker: Some(worker),
+        })
+    }
+
+    fn push(&self, job: Job) -> Result<()> {
+        if self.channel.push_data(job, &self.config)? {
+            let dropped = {
+                let mut metrics = self.metrics.lock().unwrap();
+                metrics.dropped += 1;
+                metrics.dropped
+            };
+            log::warn!(
+                "Buffer of '{}' sink is full, dropped data ({} dropped)",
+                self.name,
+                dropped
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Sink for ThreadedSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+        self.push(Job::Measurement(measurement.clone(), Instant::now()))
+    }
+
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()> {
+        self.push(Job::Aggregate(aggregate.clone(), Instant::now()))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let (done, wait) = mpsc::channel();
+        self.channel.push_control(Job::Flush(done));
+        wait.recv().map_err(|_| stopped())?
+    }
+
+    fn metrics(&self) -> SinkMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+}
+
+impl Drop for ThreadedSink {
+    /// Stop the worker once all buffered data has been processed.
+    fn drop(&mut self) {
+        self.channel.push_control(Job::Stop);
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log::error!("Worker of '{}' sink panicked", self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    struct GatedSink {
+        gate: Arc<Mutex<()>>,
+        started: mpsc::Sender<()>,
+        received: Arc<Mutex<Vec<i64>>>,
+    }
+
+    impl Sink for GatedSink {
+        fn name(&self) -> &str {
+            "gated"
+        }
+
+        fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+            self.started.send(()).unwrap();
+            let _gate = self.gate.lock().unwrap();
+            let timestamp = measurement.timestamp.timestamp();
+            self.received.lock().unwrap().push(timestamp);
+            Ok(())
+        }
+
+        fn log_aggregate(&mut self, _: &AggregateSensors) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn measurement(secs: i64) -> DhtSensors {
+        DhtSensors {
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            unit: Default::default(),
+            data: Default::default(),
+            errors: Default::default(),
+        }
+    }
+
+    /// Fill a sink with a buffer of 2 while its worker is stuck on the first measurement.
+    fn overflow(overflow: OverflowPolicy) -> (Vec<i64>, SinkMetrics) {
+        let gate = Arc::new(Mutex::new(()));
+        let (started, wait) = mpsc::channel();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = GatedSink {
+            gate: gate.clone(),
+            started,
+            received: received.clone(),
+        };
+        let config = DispatchConfig {
+            capacity: 2,
+            overflow,
+        };
+        let mut sink = ThreadedSink::new(Box::new(sink), config).unwrap();
+
+        let guard = gate.lock().unwrap();
+        sink.log_measurement(&measurement(1)).unwrap();
+        wait.recv().unwrap();
+        for secs in 2..5 {
+            sink.log_measurement(&measurement(secs)).unwrap();
+        }
+        drop(guard);
+
+        sink.flush().unwrap();
+        let received = received.lock().unwrap().clone();
+        (received, sink.metrics())
+    }
+
+    // Test that the newest data is dropped when the buffer is full
+    #[test]
+    fn test_drop_newest() {
+        let (received, metrics) = overflow(OverflowPolicy::DropNewest);
+        assert_eq!(received, vec![1, 2, 3]);
+        assert_eq!(metrics.dropped, 1);
+        assert_eq!(metrics.sent, 3);
+        assert!(metrics.last_latency.is_some());
+    }
+
+    struct PanickingSink;
+
+    impl Sink for PanickingSink {
+        fn name(&self) -> &str {
+            "panicking"
+        }
+
+        fn log_measurement(&mut self, _: &DhtSensors) -> Result<()> {
+            panic!("sink failed");
+        }
+
+        fn log_aggregate(&mut self, _: &AggregateSensors) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Test that logging and flushing fail instead of blocking once the worker panicked
+    #[test]
+    fn test_worker_panic() {
+        let config = DispatchConfig {
+            capacity: 1,
+            overflow: OverflowPolicy::Block,
+        };
+        let mut sink = ThreadedSink::new(Box::new(PanickingSink), config).unwrap();
+        sink.log_measurement(&measurement(1)).unwrap();
+        assert_eq!(sink.flush().unwrap_err().kind(), ErrorKind::BrokenPipe);
+        for secs in 2..4 {
+            let err = sink.log_measurement(&measurement(secs)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        }
+    }
+
+    // Test that the oldest data is dropped when the buffer is full
+    #[test]
+    fn test_drop_oldest() {
+        let (received, metrics) = overflow(OverflowPolicy::DropOldest);
+        assert_eq!(received, vec![1, 3, 4]);
+        assert_eq!(metrics.dropped, 1);
+        assert_eq!(metrics.sent, 3);
+    }
+}
@@ -0,0 +1,421 @@
+//! Outlier rejection and smoothing filters applied to DHT sensor readings.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::messages::*;
+use super::Result;
+
+/// Label of the filter chain used for sensors without a chain of their own.
+pub const DEFAULT_CHAIN: &str = "*";
+
+const FIELDS: [&str; 3] = ["temperature", "humidity", "heat_index"];
+
+type Fields = [f32; 3];
+
+fn to_fields(data: &SensorData) -> Fields {
+    [data.temperature, data.humidity, data.heat_index]
+}
+
+fn from_fields(fields: Fields, extra: BTreeMap<String, f32>) -> SensorData {
+    SensorData {
+        temperature: fields[0],
+        humidity: fields[1],
+        heat_index: fields[2],
+        extra,
+    }
+}
+
+/// Per-field settings of a filter. Fields that are not set are not filtered.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FieldLimits<T> {
+    pub temperature: Option<T>,
+    pub humidity: Option<T>,
+    pub heat_index: Option<T>,
+}
+
+impl<T> Default for FieldLimits<T> {
+    fn default() -> Self {
+        FieldLimits {
+            temperature: None,
+            humidity: None,
+            heat_index: None,
+        }
+    }
+}
+
+impl<T: Copy> FieldLimits<T> {
+    fn to_array(&self) -> [Option<T>; 3] {
+        [self.temperature, self.humidity, self.heat_index]
+    }
+}
+
+/// Inclusive range of valid values.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Configuration of a single filter in a filter chain.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterConfig {
+    /// Reject readings with values outside of a range.
+    Range(FieldLimits<Range>),
+    /// Reject readings changing faster than a maximum rate per second since the last accepted
+    /// reading.
+    Rate(FieldLimits<f32>),
+    /// Replace readings by the median of the last `size` readings.
+    Median { size: usize },
+    /// Replace readings by an exponential moving average with smoothing factor `alpha`.
+    Ema { alpha: f32 },
+}
+
+/// Configuration of the filter chains applied to each sensor. Temperature settings are in
+/// degrees Celsius, as readings are normalized to Celsius when they are read.
+///
+/// Example configuration YAML (inside of `logger_config`):
+/// ```yaml
+/// filters:
+///   # Log every rejected reading using log::warn!
+///   log_rejected: true
+///   sensors:
+///     # Filter chain for sensors without a chain of their own
+///     "*":
+///       - type: range
+///         humidity: {min: 0, max: 100}
+///       - type: rate
+///         humidity: 10.0
+///       - type: median
+///         size: 5
+///       - type: ema
+///         alpha: 0.5
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FiltersConfig {
+    #[serde(default)]
+    pub log_rejected: bool,
+    pub sensors: HashMap<String, Vec<FilterConfig>>,
+}
+
+/// A stateful filter for the readings of a single sensor.
+trait Filter: Send {
+    /// Filter a reading, returning the reason for rejection if the reading is rejected.
+    fn apply(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        fields: Fields,
+    ) -> std::result::Result<Fields, String>;
+}
+
+struct RangeFilter {
+    limits: [Option<Range>; 3],
+}
+
+impl Filter for RangeFilter {
+    fn apply(&mut self, _: DateTime<Utc>, fields: Fields) -> std::result::Result<Fields, String> {
+        for (i, value) in fields.iter().enumerate() {
+            if let Some(range) = self.limits[i] {
+                if !(range.min..=range.max).contains(value) {
+                    return Err(format!(
+                        "{} {} outside of range [{}, {}]",
+                        FIELDS[i], value, range.min, range.max
+                    ));
+                }
+            }
+        }
+
+        Ok(fields)
+    }
+}
+
+struct RateFilter {
+    limits: [Option<f32>; 3],
+    last: Option<(DateTime<Utc>, Fields)>,
+}
+
+impl Filter for RateFilter {
+    fn apply(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        fields: Fields,
+    ) -> std::result::Result<Fields, String> {
+        if let Some((last_timestamp, last)) = self.last {
+            let elapsed = (timestamp - last_timestamp).num_milliseconds().max(1) as f32 / 1000.0;
+            for (i, value) in fields.iter().enumerate() {
+                if let Some(rate) = self.limits[i] {
+                    let change = (value - last[i]).abs();
+                    if change.is_nan() || change > rate * elapsed {
+                        return Err(format!(
+                            "{} changed by {} in {}s, more than {} per second",
+                            FIELDS[i], change, elapsed, rate
+                        ));
+                    }
+                }
+            }
+        }
+
+        self.last = Some((timestamp, fields));
+        Ok(fields)
+    }
+}
+
+struct MedianFilter {
+    size: usize,
+    history: VecDeque<Fields>,
+}
+
+impl Filter for MedianFilter {
+    fn apply(&mut self, _: DateTime<Utc>, fields: Fields) -> std::result::Result<Fields, String> {
+        if self.history.len() == self.size {
+            self.history.pop_front();
+        }
+        self.history.push_back(fields);
+
+        let mut median = fields;
+        for (i, median) in median.iter_mut().enumerate() {
+            let mut values: Vec<f32> = self.history.iter().map(|fields| fields[i]).collect();
+            values.sort_by(|a, b| a.total_cmp(b));
+            let mid = values.len() / 2;
+            *median = if values.len() % 2 == 1 {
+                values[mid]
+            } else {
+                (values[mid - 1] + values[mid]) / 2.0
+            };
+        }
+
+        Ok(median)
+    }
+}
+
+struct EmaFilter {
+    alpha: f32,
+    state: Option<Fields>,
+}
+
+impl Filter for EmaFilter {
+    fn apply(&mut self, _: DateTime<Utc>, fields: Fields) -> std::result::Result<Fields, String> {
+        let mut average = fields;
+        if let Some(state) = self.state {
+            for (i, average) in average.iter_mut().enumerate() {
+                *average = self.alpha * fields[i] + (1.0 - self.alpha) * state[i];
+            }
+        }
+
+        self.state = Some(average);
+        Ok(average)
+    }
+}
+
+impl FilterConfig {
+    /// Build a filter from its config, returning an `InvalidData` error if the config is invalid.
+    fn build(&self) -> Result<Box<dyn Filter>> {
+        let filter: Box<dyn Filter> = match self {
+            FilterConfig::Range(limits) => Box::new(RangeFilter {
+                limits: limits.to_array(),
+            }),
+            FilterConfig::Rate(limits) => Box::new(RateFilter {
+                limits: limits.to_array(),
+                last: None,
+            }),
+            FilterConfig::Median { size } => {
+                if *size == 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Median filter size must be positive",
+                    ));
+                }
+                Box::new(MedianFilter {
+                    size: *size,
+                    history: VecDeque::with_capacity(*size),
+                })
+            }
+            FilterConfig::Ema { alpha } => {
+                if !(0.0..=1.0).contains(alpha) || *alpha == 0.0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("EMA alpha must be in (0, 1], got value: {}", alpha),
+                    ));
+                }
+                Box::new(EmaFilter {
+                    alpha: *alpha,
+                    state: None,
+                })
+            }
+        };
+        Ok(filter)
+    }
+}
+
+/// Filter chains for every sensor, along with counters of rejected readings.
+pub struct SensorFilters {
+    config: FiltersConfig,
+    chains: HashMap<String, Vec<Box<dyn Filter>>>,
+    rejected: HashMap<String, u64>,
+}
+
+impl SensorFilters {
+    /// Create the filter chains from a config. Every filter config is validated immediately,
+    /// returning an `InvalidData` error if one is invalid.
+    pub fn new(config: FiltersConfig) -> Result<SensorFilters> {
+        for filter in config.sensors.values().flatten() {
+            filter.build()?;
+        }
+
+        Ok(SensorFilters {
+            config,
+            chains: HashMap::new(),
+            rejected: HashMap::new(),
+        })
+    }
+
+    /// Filter all sensors of a measurement. Rejected sensor readings are removed from the
+    /// measurement and counted.
+    pub fn apply(&mut self, measurement: DhtSensors) -> DhtSensors {
+        let mut data = HashMap::new();
+        for (label, sensor) in measurement.data.into_iter() {
+            let config = match self
+                .config
+                .sensors
+                .get(&label)
+                .or_else(|| self.config.sensors.get(DEFAULT_CHAIN))
+            {
+                Some(config) => config,
+                None => {
+                    data.insert(label, sensor);
+                    continue;
+                }
+            };
+
+            let chain = self.chains.entry(label.clone()).or_insert_with(|| {
+                config
+                    .iter()
+                    .map(|filter| filter.build().expect("filters are validated on creation"))
+                    .collect()
+            });
+
+            let mut fields = to_fields(&sensor);
+            let mut rejected = None;
+            for filter in chain.iter_mut() {
+                match filter.apply(measurement.timestamp, fields) {
+                    Ok(filtered) => fields = filtered,
+                    Err(reason) => {
+                        rejected = Some(reason);
+                        break;
+                    }
+                }
+            }
+
+            match rejected {
+                Some(reason) => {
+                    *self.rejected.entry(label.clone()).or_insert(0) += 1;
+                    if self.config.log_rejected {
+                        log::warn!(
+                            sensor = label.as_str(), error_kind = "rejected";
+                            "Rejected reading of '{}' sensor: {}", label, reason
+                        );
+                    } else {
+                        log::trace!("Rejected reading of '{}' sensor: {}", label, reason);
+                    }
+                }
+                None => {
+                    data.insert(label, from_fields(fields, sensor.extra));
+                }
+            }
+        }
+
+        DhtSensors {
+            timestamp: measurement.timestamp,
+            unit: measurement.unit,
+            data,
+            errors: measurement.errors,
+        }
+    }
+
+    /// Get the number of rejected readings for every sensor with rejected readings.
+    pub fn rejected(&self) -> &HashMap<String, u64> {
+        &self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn reading(secs: i64, label: &str, value: f32) -> DhtSensors {
+        let mut data = HashMap::new();
+        data.insert(
+            String::from(label),
+            SensorData {
+                temperature: value,
+                humidity: value,
+                heat_index: value,
+                ..Default::default()
+            },
+        );
+
+        DhtSensors {
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            unit: Default::default(),
+            data,
+            errors: HashMap::new(),
+        }
+    }
+
+    fn filters(yaml: &str) -> SensorFilters {
+        SensorFilters::new(serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    // Test that range and rate filters reject spikes and count them per sensor
+    #[test]
+    fn test_reject_outliers() {
+        let mut filters = filters(
+            r#"
+            sensors:
+              "*":
+                - type: range
+                  humidity: {min: 0, max: 100}
+                - type: rate
+                  humidity: 5.0
+            "#,
+        );
+
+        assert_eq!(filters.apply(reading(0, "a", 45.0)).data.len(), 1);
+        assert_eq!(filters.apply(reading(0, "b", 101.0)).data.len(), 0);
+        assert_eq!(filters.apply(reading(2, "a", 99.0)).data.len(), 0);
+        assert_eq!(filters.apply(reading(4, "a", 50.0)).data.len(), 1);
+        assert_eq!(filters.apply(reading(4, "b", f32::NAN)).data.len(), 0);
+
+        assert_eq!(filters.rejected().get("a"), Some(&1));
+        assert_eq!(filters.rejected().get("b"), Some(&2));
+    }
+
+    // Test that smoothing filters are applied in order and only to configured sensors
+    #[test]
+    fn test_smoothing() {
+        let mut filters = filters(
+            r#"
+            sensors:
+              a:
+                - type: median
+                  size: 3
+                - type: ema
+                  alpha: 0.5
+            "#,
+        );
+
+        let value = |data: DhtSensors, label: &str| data.data.get(label).unwrap().humidity;
+        assert_eq!(value(filters.apply(reading(0, "a", 10.0)), "a"), 10.0);
+        assert_eq!(value(filters.apply(reading(1, "a", 90.0)), "a"), 30.0);
+        assert_eq!(value(filters.apply(reading(2, "a", 20.0)), "a"), 25.0);
+        assert_eq!(value(filters.apply(reading(3, "b", 90.0)), "b"), 90.0);
+        assert!(filters.rejected().is_empty());
+    }
+}
@@ -0,0 +1,654 @@
+//! Reading DHT sensors directly through the GPIO lines of a Linux GPIO chip, without a device
+//! sending JSON.
+//!
+//! The DHT single-wire protocol: the host drives the line low to request a reading and releases
+//! it. The sensor answers with 80 µs low and 80 µs high, then sends 40 bits, each one a 50 µs low
+//! pulse followed by a high pulse of 26-28 µs for a 0 or 70 µs for a 1. The 5 bytes are the
+//! humidity, the temperature and a checksum.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use super::sensors::SensorModel;
+use super::source::Source;
+use super::validation::heat_index;
+use super::{Result, StopHandle};
+
+/// Time during which the edges of a response are recorded after the start signal. A complete
+/// response takes about 5 ms.
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Longest time to sleep between checks of the stop handle.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn default_interval() -> u64 {
+    2
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// An edge of the signal on a GPIO line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub rising: bool,
+    pub timestamp_ns: u64,
+}
+
+/// Access to the GPIO lines that DHT sensors are attached to.
+pub trait DhtChip: Send {
+    /// Send the start signal on a line by driving it low for `start`, then release the line and
+    /// record the edges of the response of the sensor until `timeout` elapses.
+    fn read_edges(&mut self, line: u32, start: Duration, timeout: Duration) -> Result<Vec<Edge>>;
+}
+
+/// A DHT sensor attached to a GPIO line.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GpioSensor {
+    pub line: u32,
+    pub model: SensorModel,
+}
+
+/// Configuration of DHT sensors read through GPIO, used with a `gpio://` port. Readings are sent
+/// in degrees Celsius.
+///
+/// Example configuration YAML (at the top level of the config):
+/// ```yaml
+/// port: gpio:///dev/gpiochip0
+/// gpio:
+///   # Seconds between readings
+///   interval: 2
+///   # Attempts per reading
+///   retries: 3
+///   sensors:
+///     living_room:
+///       line: 4
+///       model: DHT22
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GpioConfig {
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    pub sensors: HashMap<String, GpioSensor>,
+}
+
+impl GpioConfig {
+    /// Check that sensors are configured and can be read, returning an `InvalidData` error
+    /// otherwise.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::new(ErrorKind::InvalidData, reason.to_owned()));
+        if self.sensors.is_empty() {
+            return invalid("gpio.sensors must not be empty");
+        }
+        if self.interval < 1 {
+            return invalid("gpio.interval must be at least 1");
+        }
+        if self.retries < 1 {
+            return invalid("gpio.retries must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Get the duration of the start signal of a sensor model.
+fn start_signal(model: SensorModel) -> Duration {
+    match model {
+        SensorModel::Dht11 => Duration::from_millis(20),
+        SensorModel::Dht22 | SensorModel::Am2302 => Duration::from_micros(1100),
+    }
+}
+
+/// Get the minimum time between two readings of a sensor model.
+fn min_interval(model: SensorModel) -> Duration {
+    match model {
+        SensorModel::Dht11 => Duration::from_secs(1),
+        SensorModel::Dht22 | SensorModel::Am2302 => Duration::from_secs(2),
+    }
+}
+
+/// Decode the 5 bytes sent by a sensor from the edges of its response, verifying the checksum.
+///
+/// Each bit is a low pulse followed by a high pulse, and is a 1 if the high pulse is longer than
+/// the low one. The last 40 pulse pairs are the data bits, so missing the start of the response
+/// is not an error.
+pub fn decode(edges: &[Edge]) -> Result<[u8; 5]> {
+    let pulses: Vec<(u64, u64)> = edges
+        .windows(3)
+        .filter(|edges| !edges[0].rising && edges[1].rising && !edges[2].rising)
+        .map(|edges| {
+            let low = edges[1].timestamp_ns.saturating_sub(edges[0].timestamp_ns);
+            let high = edges[2].timestamp_ns.saturating_sub(edges[1].timestamp_ns);
+            (low, high)
+        })
+        .collect();
+    if pulses.len() < 40 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("incomplete response: {} of 40 bits", pulses.len()),
+        ));
+    }
+
+    let mut bytes = [0u8; 5];
+    for (i, (low, high)) in pulses[pulses.len() - 40..].iter().enumerate() {
+        if high > low {
+            bytes[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+
+    let checksum = bytes[..4]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if checksum != bytes[4] {
+        return Err(Error::new(ErrorKind::InvalidData, "checksum mismatch"));
+    }
+    Ok(bytes)
+}
+
+/// Convert the bytes sent by a sensor to the temperature in degrees Celsius and the humidity.
+pub fn convert(model: SensorModel, bytes: &[u8; 5]) -> (f32, f32) {
+    let (temperature, humidity) = match model {
+        SensorModel::Dht11 => (
+            bytes[2] as f32 + (bytes[3] & 0x7f) as f32 * 0.1,
+            bytes[0] as f32 + bytes[1] as f32 * 0.1,
+        ),
+        SensorModel::Dht22 | SensorModel::Am2302 => (
+            u16::from_be_bytes([bytes[2] & 0x7f, bytes[3]]) as f32 * 0.1,
+            u16::from_be_bytes([bytes[0], bytes[1]]) as f32 * 0.1,
+        ),
+    };
+
+    let negative = match model {
+        SensorModel::Dht11 => bytes[3] & 0x80 != 0,
+        SensorModel::Dht22 | SensorModel::Am2302 => bytes[2] & 0x80 != 0,
+    };
+    if negative {
+        (-temperature, humidity)
+    } else {
+        (temperature, humidity)
+    }
+}
+
+/// A source reading DHT sensors through GPIO lines, producing frames in the JSON format of the
+/// serial interface. A sensor that cannot be read after all retries is reported with an error.
+///
+/// Reads wait for the interval between readings and between retries, and return an
+/// `Interrupted` error as soon as the logger is stopped.
+pub struct GpioSource<C> {
+    name: String,
+    chip: C,
+    config: GpioConfig,
+    retry_delay: Option<Duration>,
+    last_read: Option<Instant>,
+    stop: StopHandle,
+}
+
+impl<C: DhtChip> GpioSource<C> {
+    /// Create a source reading the sensors of the config through a chip.
+    pub fn new(name: String, chip: C, config: GpioConfig) -> GpioSource<C> {
+        GpioSource {
+            name,
+            chip,
+            config,
+            retry_delay: None,
+            last_read: None,
+            stop: StopHandle::default(),
+        }
+    }
+
+    /// Set the delay between attempts to read a sensor, which defaults to the minimum time
+    /// between readings of its model.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> GpioSource<C> {
+        self.retry_delay = Some(retry_delay);
+        self
+    }
+
+    /// Sleep for `duration`, returning an `Interrupted` error early if the logger is stopped.
+    fn sleep(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.stop.is_stopped() {
+                return Err(Error::new(ErrorKind::Interrupted, "DHT logger stopped"));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            thread::sleep(remaining.min(STOP_POLL_INTERVAL));
+        }
+    }
+
+    /// Read a sensor, retrying on errors. Returns an `Interrupted` error if the logger is stopped
+    /// while waiting to retry.
+    fn read_sensor(&mut self, sensor: &GpioSensor) -> Result<(f32, f32)> {
+        let mut retry = 0;
+        loop {
+            let result = self
+                .chip
+                .read_edges(sensor.line, start_signal(sensor.model), RESPONSE_TIMEOUT)
+                .and_then(|edges| decode(&edges));
+            match result {
+                Ok(bytes) => return Ok(convert(sensor.model, &bytes)),
+                Err(err) => {
+                    retry += 1;
+                    log::trace!("Failed to read GPIO line {}: {}", sensor.line, err);
+                    if retry >= self.config.retries {
+                        return Err(err);
+                    }
+                    self.sleep(
+                        self.retry_delay
+                            .unwrap_or_else(|| min_interval(sensor.model)),
+                    )?;
+                }
+            }
+        }
+    }
+}
+
+impl<C: DhtChip> Source for GpioSource<C> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if let Some(last_read) = self.last_read {
+            let interval = Duration::from_secs(self.config.interval);
+            self.sleep(interval.saturating_sub(last_read.elapsed()))?;
+        }
+        self.last_read = Some(Instant::now());
+
+        let mut sensors: Vec<(String, GpioSensor)> =
+            self.config.sensors.clone().into_iter().collect();
+        sensors.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut frame = Map::new();
+        for (label, sensor) in sensors {
+            let value = match self.read_sensor(&sensor) {
+                Ok((t, h)) => json!({"t": t, "h": h, "hi": heat_index(t, h)}),
+                Err(err) if err.kind() == ErrorKind::Interrupted => return Err(err),
+                Err(err) => json!({"e": err.to_string()}),
+            };
+            frame.insert(label, value);
+        }
+
+        let frame = serde_json::to_vec(&Value::Object(frame))?;
+        if frame.len() > buffer.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "frame exceeds the buffer",
+            ));
+        }
+        buffer[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len())
+    }
+
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn set_stop_handle(&mut self, stop: StopHandle) {
+        self.stop = stop;
+    }
+}
+
+/// Structures and requests of the Linux GPIO character device API v2, from `linux/gpio.h`.
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+mod uapi {
+    pub const LINES_MAX: usize = 64;
+    pub const MAX_NAME_SIZE: usize = 32;
+    pub const NUM_ATTRS_MAX: usize = 10;
+
+    pub const LINE_FLAG_INPUT: u64 = 1 << 2;
+    pub const LINE_FLAG_OUTPUT: u64 = 1 << 3;
+    pub const LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+    pub const LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+    pub const LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+    pub const LINE_EVENT_RISING_EDGE: u32 = 1;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct LineAttribute {
+        pub id: u32,
+        pub padding: u32,
+        /// Flags, output values or debounce period, depending on `id`.
+        pub value: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct LineConfigAttribute {
+        pub attr: LineAttribute,
+        pub mask: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct LineConfig {
+        pub flags: u64,
+        pub num_attrs: u32,
+        pub padding: [u32; 5],
+        pub attrs: [LineConfigAttribute; NUM_ATTRS_MAX],
+    }
+
+    #[repr(C)]
+    pub struct LineRequest {
+        pub offsets: [u32; LINES_MAX],
+        pub consumer: [u8; MAX_NAME_SIZE],
+        pub config: LineConfig,
+        pub num_lines: u32,
+        pub event_buffer_size: u32,
+        pub padding: [u32; 5],
+        pub fd: i32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct LineEvent {
+        pub timestamp_ns: u64,
+        pub id: u32,
+        pub offset: u32,
+        pub seqno: u32,
+        pub line_seqno: u32,
+        pub padding: [u32; 6],
+    }
+
+    const _: () = assert!(std::mem::size_of::<LineConfig>() == 272);
+    const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+    const _: () = assert!(std::mem::size_of::<LineEvent>() == 48);
+
+    /// Get the number of a read-write ioctl request, as `_IOWR` of the generic ioctl layout.
+    const fn iowr(nr: u64, size: usize) -> u64 {
+        (3 << 30) | ((size as u64) << 16) | (0xB4 << 8) | nr
+    }
+
+    pub const GET_LINE_IOCTL: u64 = iowr(0x07, std::mem::size_of::<LineRequest>());
+    pub const LINE_SET_CONFIG_IOCTL: u64 = iowr(0x0D, std::mem::size_of::<LineConfig>());
+}
+
+/// A GPIO chip accessed through the Linux GPIO character device API v2.
+///
+/// A line is requested as an output driven low for the start signal, then reconfigured as an
+/// input with edge detection on the same request, so the line is never released to another
+/// consumer and edges are timestamped by the kernel from the moment the line is an input. This
+/// API requires Linux 5.10 or later.
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+pub struct CdevChip {
+    chip: std::fs::File,
+}
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+impl CdevChip {
+    /// Open a GPIO chip, such as `/dev/gpiochip0`.
+    pub fn open(path: &std::path::Path) -> Result<CdevChip> {
+        let chip = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(CdevChip { chip })
+    }
+}
+
+#[cfg(all(feature = "gpio", target_os = "linux"))]
+impl DhtChip for CdevChip {
+    fn read_edges(&mut self, line: u32, start: Duration, timeout: Duration) -> Result<Vec<Edge>> {
+        use std::fs::File;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        const CONSUMER: &[u8] = b"dht-logger";
+        // Edges of the response, the start signal and the release of the line.
+        const MAX_EDGES: usize = 86;
+
+        // Request the line as an output driven low, which starts the start signal.
+        let mut config = uapi::LineConfig {
+            flags: uapi::LINE_FLAG_OUTPUT,
+            num_attrs: 1,
+            ..Default::default()
+        };
+        config.attrs[0] = uapi::LineConfigAttribute {
+            attr: uapi::LineAttribute {
+                id: uapi::LINE_ATTR_ID_OUTPUT_VALUES,
+                padding: 0,
+                value: 0,
+            },
+            mask: 1,
+        };
+        let mut request = uapi::LineRequest {
+            offsets: [0; uapi::LINES_MAX],
+            consumer: [0; uapi::MAX_NAME_SIZE],
+            config,
+            num_lines: 1,
+            event_buffer_size: MAX_EDGES as u32 * 2,
+            padding: [0; 5],
+            fd: -1,
+        };
+        request.offsets[0] = line;
+        request.consumer[..CONSUMER.len()].copy_from_slice(CONSUMER);
+        // SAFETY: `request` is a valid gpio_v2_line_request for the duration of the call.
+        let result = unsafe {
+            libc::ioctl(
+                self.chip.as_raw_fd(),
+                uapi::GET_LINE_IOCTL as _,
+                &mut request as *mut uapi::LineRequest,
+            )
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: the kernel returned a new file descriptor of the line request, owned from here.
+        let request_file = unsafe { File::from_raw_fd(request.fd) };
+        thread::sleep(start);
+
+        // Release the line by making it an input, recording the edges of the response.
+        let mut config = uapi::LineConfig {
+            flags: uapi::LINE_FLAG_INPUT
+                | uapi::LINE_FLAG_EDGE_RISING
+                | uapi::LINE_FLAG_EDGE_FALLING,
+            ..Default::default()
+        };
+        // SAFETY: `config` is a valid gpio_v2_line_config for the duration of the call.
+        let result = unsafe {
+            libc::ioctl(
+                request_file.as_raw_fd(),
+                uapi::LINE_SET_CONFIG_IOCTL as _,
+                &mut config as *mut uapi::LineConfig,
+            )
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut edges = Vec::new();
+        let mut events = [uapi::LineEvent::default(); 16];
+        while edges.len() < MAX_EDGES {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut poll = libc::pollfd {
+                fd: request_file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: `poll` points to a single valid pollfd for the duration of the call.
+            let ready = unsafe { libc::poll(&mut poll, 1, remaining.as_millis().max(1) as i32) };
+            if ready < 0 {
+                return Err(Error::last_os_error());
+            }
+            if ready == 0 || remaining.is_zero() {
+                break;
+            }
+
+            // SAFETY: `events` is valid for writes of its whole size, and the kernel only writes
+            // complete events.
+            let n_bytes = unsafe {
+                libc::read(
+                    request_file.as_raw_fd(),
+                    events.as_mut_ptr() as *mut libc::c_void,
+                    std::mem::size_of_val(&events),
+                )
+            };
+            if n_bytes < 0 {
+                return Err(Error::last_os_error());
+            }
+            let n_events = n_bytes as usize / std::mem::size_of::<uapi::LineEvent>();
+            edges.extend(events[..n_events].iter().map(|event| Edge {
+                rising: event.id == uapi::LINE_EVENT_RISING_EDGE,
+                timestamp_ns: event.timestamp_ns,
+            }));
+        }
+        Ok(edges)
+    }
+}
+
+/// Open a source reading the sensors of the config through a GPIO chip.
+pub fn open(chip: &std::path::Path, config: &GpioConfig) -> Result<Box<dyn Source>> {
+    #[cfg(all(feature = "gpio", target_os = "linux"))]
+    {
+        let name = format!("gpio://{}", chip.display());
+        let chip = CdevChip::open(chip)?;
+        Ok(Box::new(GpioSource::new(name, chip, config.clone())))
+    }
+
+    #[cfg(not(all(feature = "gpio", target_os = "linux")))]
+    {
+        let _ = (chip, config);
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "GPIO sources require Linux and the gpio feature",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Pulse timings in µs of a DHT22 response: 51.6 % humidity and -10.1 °C, after the response
+    /// preamble. Each pair is a low pulse and a high pulse.
+    #[rustfmt::skip]
+    const DHT22_PULSES: [(u64, u64); 41] = [
+        (81, 79),
+        // 0x02 = 0b0000_0010
+        (52, 26), (50, 27), (51, 25), (53, 27), (50, 26), (49, 28), (51, 71), (50, 27),
+        // 0x04 = 0b0000_0100
+        (50, 26), (52, 27), (51, 26), (50, 27), (49, 25), (52, 70), (50, 26), (51, 27),
+        // 0x80 = 0b1000_0000
+        (50, 69), (51, 27), (52, 26), (50, 27), (49, 26), (51, 28), (50, 26), (52, 25),
+        // 0x65 = 0b0110_0101
+        (51, 27), (50, 70), (52, 71), (49, 26), (50, 27), (51, 72), (50, 25), (52, 70),
+        // 0xEB = 0b1110_1011
+        (50, 71), (51, 69), (52, 70), (50, 26), (49, 72), (51, 27), (50, 70), (52, 71),
+    ];
+
+    fn edges(pulses: &[(u64, u64)]) -> Vec<Edge> {
+        let mut edges = Vec::new();
+        let mut timestamp_ns = 1_000_000;
+        for (low, high) in pulses {
+            edges.push(Edge {
+                rising: false,
+                timestamp_ns,
+            });
+            timestamp_ns += low * 1000;
+            edges.push(Edge {
+                rising: true,
+                timestamp_ns,
+            });
+            timestamp_ns += high * 1000;
+        }
+        edges.push(Edge {
+            rising: false,
+            timestamp_ns,
+        });
+        edges
+    }
+
+    struct MockChip {
+        responses: VecDeque<Vec<Edge>>,
+    }
+
+    impl DhtChip for MockChip {
+        fn read_edges(&mut self, _: u32, _: Duration, _: Duration) -> Result<Vec<Edge>> {
+            Ok(self.responses.pop_front().unwrap_or_default())
+        }
+    }
+
+    // Test decoding pulse timings, including a response missing its preamble and a corrupted one
+    #[test]
+    fn test_decode() {
+        let bytes = decode(&edges(&DHT22_PULSES)).unwrap();
+        assert_eq!(bytes, [0x02, 0x04, 0x80, 0x65, 0xEB]);
+        let (temperature, humidity) = convert(SensorModel::Dht22, &bytes);
+        assert!((temperature + 10.1).abs() < 1e-4);
+        assert!((humidity - 51.6).abs() < 1e-4);
+
+        assert_eq!(decode(&edges(&DHT22_PULSES[1..])).unwrap(), bytes);
+        assert!(decode(&edges(&DHT22_PULSES[2..])).is_err());
+
+        let mut corrupted = DHT22_PULSES;
+        corrupted[8].1 = 70;
+        let err = decode(&edges(&corrupted)).unwrap_err();
+        assert_eq!(err.to_string(), "checksum mismatch");
+
+        let (temperature, humidity) = convert(SensorModel::Dht11, &[45, 0, 23, 3, 71]);
+        assert!((temperature - 23.3).abs() < 1e-4);
+        assert_eq!(humidity, 45.0);
+    }
+
+    // Test that failed readings are retried and reported as sensor errors in the frame
+    #[test]
+    fn test_gpio_source() {
+        let config: GpioConfig = serde_yaml::from_str(
+            "retries: 2\nsensors:\n  a: {line: 4, model: DHT22}\n  b: {line: 5, model: AM2302}\n",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        // Sensor a fails once, sensor b fails on both attempts.
+        let chip = MockChip {
+            responses: VecDeque::from(vec![
+                edges(&DHT22_PULSES[5..]),
+                edges(&DHT22_PULSES),
+                Vec::new(),
+                Vec::new(),
+            ]),
+        };
+        let mut source =
+            GpioSource::new(String::from("gpio"), chip, config).with_retry_delay(Duration::ZERO);
+        let mut buffer = [0; 1024];
+        let n_bytes = source.read(&mut buffer).unwrap();
+        let frame: Value = serde_json::from_slice(&buffer[..n_bytes]).unwrap();
+        assert!((frame["a"]["t"].as_f64().unwrap() + 10.1).abs() < 1e-4);
+        assert_eq!(
+            frame["b"]["e"].as_str().unwrap(),
+            "incomplete response: 0 of 40 bits"
+        );
+    }
+
+    // Test that waiting between readings and retries ends once the logger is stopped
+    #[test]
+    fn test_gpio_stop() {
+        let config: GpioConfig =
+            serde_yaml::from_str("interval: 60\nsensors:\n  a: {line: 4, model: DHT22}\n").unwrap();
+        let chip = MockChip {
+            responses: VecDeque::new(),
+        };
+        let mut source = GpioSource::new(String::from("gpio"), chip, config);
+        let stop = StopHandle::default();
+        source.set_stop_handle(stop.clone());
+
+        let started = Instant::now();
+        let mut buffer = [0; 1024];
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            stop.stop();
+        });
+        // The first reading fails and waits for 2 s before retrying, the second one waits for
+        // the interval.
+        let err = source.read(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        stopper.join().unwrap();
+        assert!(source.read(&mut buffer).is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -23,23 +23,48 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::File;
 use std::io::{Error, ErrorKind};
-use std::net::{SocketAddrV4, UdpSocket};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use chrono::Utc;
-use log;
-use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use serde_yaml;
 use serialport::{self, SerialPort};
 
+pub mod aggregate;
+#[cfg(feature = "async")]
+pub mod async_logger;
+pub mod auth;
+pub mod check;
+pub mod config;
+pub mod dispatch;
+pub mod filters;
+pub mod gpio;
 pub mod messages;
+pub mod parser;
+pub mod pipeline;
+pub mod queue;
+pub mod sensors;
+pub mod serial;
+pub mod sinks;
+pub mod source;
+#[cfg(unix)]
+pub mod systemd;
+pub mod udp;
+pub mod units;
+pub mod validation;
+pub mod wire;
+pub use config::DhtLoggerConfig;
 use messages::*;
 pub use messages::{Measurement, SensorData};
+use parser::FrameParser;
+use pipeline::Pipeline;
+use sensors::SensorRegistry;
+use sinks::{Sink, SinkMetrics, SinksConfig};
+use source::{Reconnecting, Source, SourceAddr};
 
 #[cfg(test)]
 pub mod tests;
@@ -50,49 +75,129 @@ pub type Result<T> = std::result::Result<T, Error>;
 const BUFFER_SIZE: usize = 1024;
 const TIMEOUT: Duration = Duration::from_secs(4);
 
-/// Configuration of a DHT Logger client.
-///
-/// Example configuration YAML:
-/// ```yaml
-/// # Serial port configuration
-/// port: /dev/ttyUSB0
-/// baud: 115200
-///
-/// # Configure how the sensor data is logged.
-/// logger_config:
-///   # verbose: true tells the logger to
-///   # use log::info! for sensor readings
-///   verbose: true
-/// ```
-#[derive(Debug, Deserialize, Serialize)]
-pub struct DhtLoggerConfig {
-    pub port: PathBuf,
-    pub baud: u32,
-    pub logger_config: HashMap<String, Value>,
+/// Callback receiving every measurement logged by a DHT logger.
+pub type ReadingCallback = Box<dyn FnMut(&DhtSensors) + Send>;
+
+/// Handle to stop the read loop of a DHT logger, possibly from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
 }
 
-impl DhtLoggerConfig {
-    /// Load a YAML config file into a config struct
-    pub fn load_yaml(config_file: &Path) -> DhtLoggerConfig {
-        let config_file = File::open(config_file).unwrap();
-        match serde_yaml::from_reader(config_file) {
-            Ok(dht_logger) => dht_logger,
-            Err(_) => panic!("YAML parse error in DHT logger config."),
-        }
+impl StopHandle {
+    /// Request the DHT logger to stop. The logger stops after the read in progress.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the DHT logger was requested to stop.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Handle to request a reload of the logger config, possibly from another thread. The reload is
+/// applied by `DhtLogger::run` between readings.
+#[derive(Clone, Debug, Default)]
+pub struct ReloadHandle {
+    pending: Arc<Mutex<Option<HashMap<String, Value>>>>,
+    applied: Arc<Mutex<Option<HashMap<String, Value>>>>,
+}
+
+impl ReloadHandle {
+    /// Request the DHT logger to reload with a new logger config, replacing any pending request.
+    pub fn reload(&self, logger_config: HashMap<String, Value>) {
+        *self.pending.lock().unwrap() = Some(logger_config);
+    }
+
+    /// Get the logger config of the last successful reload, or `None` if no reload succeeded yet.
+    pub fn applied(&self) -> Option<HashMap<String, Value>> {
+        self.applied.lock().unwrap().clone()
+    }
+
+    fn take(&self) -> Option<HashMap<String, Value>> {
+        self.pending.lock().unwrap().take()
+    }
+
+    fn set_applied(&self, logger_config: HashMap<String, Value>) {
+        *self.applied.lock().unwrap() = Some(logger_config);
+    }
+}
+
+/// Everything a DHT logger builds from its logger config, before the sinks are opened.
+struct ComponentsConfig {
+    registry: Arc<SensorRegistry>,
+    parser: FrameParser,
+    pipeline: Pipeline,
+    sinks: SinksConfig,
+}
+
+impl ComponentsConfig {
+    /// Parse the logger config, returning an `InvalidData` error if it is invalid.
+    fn parse(logger_config: &HashMap<String, Value>) -> Result<ComponentsConfig> {
+        let registry = Arc::new(SensorRegistry::from_logger_config(logger_config)?);
+        let parser = FrameParser::from_logger_config(logger_config, registry.clone())?;
+        let sinks = SinksConfig::from_logger_config(logger_config)?;
+        let pipeline = Pipeline::from_logger_config(logger_config, &sinks.names())?;
+
+        Ok(ComponentsConfig {
+            registry,
+            parser,
+            pipeline,
+            sinks,
+        })
+    }
+
+    fn open(self) -> Result<Components> {
+        Ok(Components {
+            parser: self.parser,
+            pipeline: self.pipeline,
+            sinks: self.sinks.open(&self.registry)?,
+        })
+    }
+}
+
+/// Everything a DHT logger builds from its logger config.
+struct Components {
+    parser: FrameParser,
+    pipeline: Pipeline,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Components {
+    fn new(logger_config: &HashMap<String, Value>) -> Result<Components> {
+        ComponentsConfig::parse(logger_config)?.open()
     }
 }
 
 /// DHT Logger client.
 ///
-/// This is for reading data over serial and logging it using various means.
+/// This is for reading data over serial, or any other `Source`, and logging it using various
+/// means.
 ///
 /// Supported logging methods:
 /// * `verbose`: Log incoming data using `log::info!`
+/// * `udp`: Send data as compact JSON, CBOR or MessagePack to a list of UDP addresses, in the
+///   wire format configured by `wire` (see the `wire` module). Multicast groups and broadcast
+///   addresses are supported, with the socket configured by `udp_options` (see `UdpOptions`).
+/// * `csv`: Append data to a CSV file, including the metadata of each sensor.
+///
+/// Sensor labels are described by the metadata in `sensors`. Readings of sensors without metadata
+/// are accepted, logged or rejected according to `unknown_sensors`.
+///
+/// Readings are normalized to Celsius from the `input_unit` of the device, and converted to the
+/// `units` of each sink in the `sinks` options. Readings are passed through the filter chains in
+/// the `filters` config before being logged. Sinks listed in the `aggregate` config receive
+/// aggregates over tumbling time windows instead of raw readings. Every sink runs on its own
+/// worker thread, buffering data according to the `dispatch` option of the sink.
 pub struct DhtLogger {
-    port: RefCell<Box<dyn SerialPort>>,
-    verbose: bool,
-    udp_addrs: Vec<SocketAddrV4>,
-    udp_socket: Option<UdpSocket>,
+    port: RefCell<Box<dyn Source>>,
+    parser: RefCell<FrameParser>,
+    pipeline: RefCell<Pipeline>,
+    sinks: RefCell<Vec<Box<dyn Sink>>>,
+    callbacks: RefCell<Vec<ReadingCallback>>,
+    stop: StopHandle,
+    reloads: ReloadHandle,
 }
 
 impl DhtLogger {
@@ -102,138 +207,138 @@ impl DhtLogger {
     /// * `port`: An interface to use as a serial port.
     /// * `logger_config`: Configure how data is logged. See the `DhtLoggerConfig` documentation.
     pub fn new(port: Box<dyn SerialPort>, logger_config: HashMap<String, Value>) -> DhtLogger {
-        let verbose = if let Some(verbose) = logger_config.get("verbose") {
-            if let Value::Bool(verbose) = verbose {
-                *verbose
-            } else {
-                panic!("logger.verbose must be boolean, got value: {}", verbose)
-            }
-        } else {
-            false
-        };
+        DhtLogger::from_source(Box::new(port), logger_config)
+    }
 
-        let default = Value::Array(Vec::new());
-        let udp_addrs: Vec<SocketAddrV4> = logger_config
-            .get("udp")
-            .unwrap_or(&default)
-            .as_array()
-            .expect("logger.udp must be a list")
-            .iter()
-            .map(|addr| {
-                addr.as_str().expect(&format!(
-                    "UDP addresses must be strings, got value: {}",
-                    addr
-                ))
-            })
-            .map(|addr| {
-                addr.parse()
-                    .expect(&format!("Failed to parse IP:PORT, got value: {}", addr))
-            })
-            .collect();
-
-        let udp_socket = match udp_addrs.len() {
-            0 => None,
-            _ => Some(UdpSocket::bind("0.0.0.0:0").unwrap()),
-        };
+    /// Create a DHT logger reading from any source, such as a TCP stream. Panics if the logger
+    /// config is invalid or a sink cannot be opened, see `try_from_source`.
+    ///
+    /// Args:
+    /// * `source`: A source of data in the format of the serial interface.
+    /// * `logger_config`: Configure how data is logged. See the `DhtLoggerConfig` documentation.
+    pub fn from_source(
+        source: Box<dyn Source>,
+        logger_config: HashMap<String, Value>,
+    ) -> DhtLogger {
+        DhtLogger::try_from_source(source, logger_config).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Create a DHT logger reading from any source, returning an `InvalidData` error if the logger
+    /// config is invalid, or the error of opening a sink.
+    pub fn try_from_source(
+        mut source: Box<dyn Source>,
+        logger_config: HashMap<String, Value>,
+    ) -> Result<DhtLogger> {
+        let components = Components::new(&logger_config)?;
+        let stop = StopHandle::default();
+        source.set_stop_handle(stop.clone());
+
+        Ok(DhtLogger {
+            port: RefCell::new(source),
+            parser: RefCell::new(components.parser),
+            pipeline: RefCell::new(components.pipeline),
+            sinks: RefCell::new(components.sinks),
+            callbacks: RefCell::new(Vec::new()),
+            stop,
+            reloads: ReloadHandle::default(),
+        })
+    }
+
+    /// Reload the logger config, replacing the sinks, sensor metadata, filters and aggregation
+    /// without touching the serial port. The new logger config is validated first. Then the
+    /// logging channels are flushed, any partially filled aggregation window is sent, and the new
+    /// sinks are opened.
+    ///
+    /// If the new logger config is invalid, an `InvalidData` error is returned before flushing and
+    /// the old one stays in use. If a new sink cannot be opened, its error is returned and the old
+    /// config stays in use, with a new aggregation window.
+    pub fn reload(&self, logger_config: HashMap<String, Value>) -> Result<()> {
+        let config = ComponentsConfig::parse(&logger_config)?;
 
-        DhtLogger {
-            port: RefCell::new(port),
-            verbose,
-            udp_addrs,
-            udp_socket,
+        // Flush before opening, so that the old sinks are idle while the new sinks open the same
+        // files and queues.
+        if let Err(err) = self.flush() {
+            log::warn!("Failed to flush before reloading: {}", err);
         }
+
+        let components = config.open()?;
+        *self.parser.borrow_mut() = components.parser;
+        *self.pipeline.borrow_mut() = components.pipeline;
+        *self.sinks.borrow_mut() = components.sinks;
+        log::info!("Reloaded logger config");
+        Ok(())
     }
 
-    /// Create a DHT logger from a DhtLoggerConfig.
-    pub fn from_config(config: &DhtLoggerConfig) -> DhtLogger {
-        let port = serialport::new(config.port.to_str().unwrap(), config.baud)
-            .timeout(TIMEOUT)
-            .open()
-            .expect(&format!(
-                "Failed to open port: {}",
-                config.port.to_str().unwrap()
-            ));
+    /// Use an existing handle to request reloads, for example one shared with a signal handler.
+    pub fn with_reload_handle(mut self, reloads: ReloadHandle) -> DhtLogger {
+        self.reloads = reloads;
+        self
+    }
+
+    /// Get a handle to request reloads of the logger config while the logger runs.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reloads.clone()
+    }
+
+    /// Create a DHT logger from a DhtLoggerConfig, returning an error if the source or a sink
+    /// cannot be opened, or an `InvalidData` error if the logger config is invalid. The source is
+    /// reopened whenever the connection to it is lost, except for input read until its end.
+    pub fn open(config: &DhtLoggerConfig) -> Result<DhtLogger> {
+        let source = config.open_source()?;
+        let name = source
+            .name()
+            .unwrap_or_else(|| config.port.to_string_lossy().into_owned());
+        let logger_config = config.logger_config.to_owned();
+        if let SourceAddr::Input(_) = config.source() {
+            return DhtLogger::try_from_source(source, logger_config);
+        }
 
-        // trace log serial port parameters
-        log::trace!("Data bits: {:?}", port.data_bits());
-        log::trace!("Flow control: {:?}", port.flow_control());
-        log::trace!("Parity: {:?}", port.parity());
-        log::trace!("Stop bits: {:?}", port.stop_bits());
-        log::trace!("Timeout: {:?}", port.timeout());
+        let config = config.clone();
+        let source = Reconnecting::new(name, source, move || config.open_source());
+        DhtLogger::try_from_source(Box::new(source), logger_config)
+    }
 
-        DhtLogger::new(port, config.logger_config.to_owned())
+    /// Create a DHT logger from a DhtLoggerConfig.
+    pub fn from_config(config: &DhtLoggerConfig) -> DhtLogger {
+        DhtLogger::open(config)
+            .unwrap_or_else(|err| panic!("Failed to open port {}: {}", config.port.display(), err))
     }
 
-    /// Get the name of the serial port.
+    /// Get the name of the serial port, or of the source the logger reads from.
     pub fn port(&self) -> Option<PathBuf> {
-        match self.port.borrow().name() {
-            Some(name) => Some(Path::new(&name).to_path_buf()),
-            None => None,
-        }
+        self.port
+            .borrow()
+            .name()
+            .map(|name| Path::new(&name).to_path_buf())
     }
 
     /// Read sensor data over serial and return it. This blocks until data is readable over the
-    /// serial interface or a timeout occurs.
+    /// serial interface or a timeout occurs. Temperatures are returned in degrees Celsius.
+    ///
+    /// Sensors reporting an error, or a reading that is invalid for the model of the sensor, are
+    /// listed in the `errors` of the result. Readings of unknown sensors are dropped if the logger
+    /// is configured to reject them.
     pub fn read_sensor(&self) -> Result<DhtSensors> {
         let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
         let n_bytes = self.port.borrow_mut().read(&mut buffer)?;
-        let buffer = &buffer[..n_bytes];
-        if let Ok(buffer) = std::str::from_utf8(&buffer) {
-            log::trace!("got bytes: {}", buffer);
-        }
-        let timestamp = Utc::now();
-        let raw = match serde_json::from_slice::<Value>(&buffer)? {
-            Value::Object(map) => map,
-            _ => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "DHT logger data must be a JSON mapping",
-                ))
-            }
-        };
-
-        let mut sensors = HashMap::new();
-        for (key, value) in raw.iter() {
-            let value = if let Value::Object(map) = value {
-                map
-            } else {
-                panic!("Sensor value must be a JSON mapping, got value: {}", value);
-            };
-
-            let measurement = if let Some(error) = value.get("e") {
-                let error = if let Value::String(error) = error {
-                    error
-                } else {
-                    panic!("Error value must be a string, got value: {}", error);
-                };
-                Measurement::new(None, Some(error))
-            } else {
-                let raw: DhtDataRaw = serde_json::from_value(Value::Object(value.clone()))?;
-                Measurement::new(Some(SensorData::from(raw)), None)
-            };
-
-            if let Some(error) = measurement.get_error() {
-                log::warn!("Error reading '{}' sensor: {}", key, error);
-                continue;
-            }
-
-            let data = measurement.get_data().unwrap();
-            sensors.insert(String::from(key), data);
-        }
-
-        Ok(DhtSensors {
-            timestamp,
-            data: sensors,
-        })
+        self.parser.borrow().parse(&buffer[..n_bytes], Utc::now())
     }
 
     /// Wait for the sensor to return data for a specified amount of retries. If the number of
     /// attempts to read data exceed the allowed number of retries, the last error message is
     /// returned. If an error occurs, this function sleeps for 100s. All sensor read errors are
-    /// logged to `log::trace!` as they arrive.
+    /// logged to `log::trace!` as they arrive. Returns an `Interrupted` error if the logger is
+    /// stopped while waiting, and an `UnexpectedEof` error once the end of the input is reached.
     pub fn wait_for_sensor(&self, retries: u32) -> Result<DhtSensors> {
         let mut retry: u32 = 0;
         loop {
+            if self.stop.is_stopped() {
+                return Err(Error::new(ErrorKind::Interrupted, "DHT logger stopped"));
+            }
+            if self.is_finished() {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "end of input"));
+            }
+
             match self.read_sensor() {
                 Ok(measurement) => {
                     return Ok(measurement);
@@ -252,27 +357,134 @@ impl DhtLogger {
 
     /// Log a measurement to the all of the logging channels
     /// configured in the logger config for the DHT Logger.
+    ///
+    /// The measurement is filtered first, dropping rejected sensor readings. Sinks configured for
+    /// aggregation only receive data when the measurement completes an aggregation window. Sinks
+    /// run on their own worker threads, which log their errors, so an error is only returned if
+    /// handing the data to a sink fails.
     pub fn log_measurement(&self, measurement: DhtSensors) -> Result<()> {
-        // Verbose logging
-        let data_pretty = serde_json::to_string_pretty(&measurement)?;
-        let data_pretty = format!("Received measurement:\n{}", data_pretty);
-        if self.verbose {
-            log::info!("{}", data_pretty);
-        } else {
-            log::debug!("{}", data_pretty);
+        let mut pipeline = self.pipeline.borrow_mut();
+        let (measurement, aggregate) = pipeline.process(measurement);
+        for callback in self.callbacks.borrow_mut().iter_mut() {
+            callback(&measurement);
         }
 
-        // UDP logging
-        if let Some(udp_socket) = &self.udp_socket {
-            let data_json = serde_json::to_vec(&DhtSensorsSerde::from(measurement))?;
-            log::trace!("{}", std::str::from_utf8(data_json.as_slice()).unwrap());
-            for addr in self.udp_addrs.iter() {
-                let bytes_sent = udp_socket.send_to(data_json.as_slice(), addr)?;
-                log::trace!("Sent {} bytes to UDP addr: {:?}", bytes_sent, addr);
+        let mut result = Ok(());
+        for sink in self.sinks.borrow_mut().iter_mut() {
+            let logged = if pipeline.aggregates(sink.name()) {
+                match &aggregate {
+                    Some(aggregate) => sink.log_aggregate(aggregate),
+                    None => Ok(()),
+                }
+            } else {
+                sink.log_measurement(&measurement)
+            };
+
+            if let Err(err) = logged {
+                result = result.and(Err(err));
             }
         }
 
-        Ok(())
+        result
+    }
+
+    /// Get the metrics of every sink, keyed by sink name.
+    pub fn sink_metrics(&self) -> HashMap<String, SinkMetrics> {
+        self.sinks
+            .borrow()
+            .iter()
+            .map(|sink| (sink.name().to_owned(), sink.metrics()))
+            .collect()
+    }
+
+    /// Get the registry of sensor metadata.
+    pub fn sensors(&self) -> Arc<SensorRegistry> {
+        self.parser.borrow().registry().clone()
+    }
+
+    /// Get the number of readings rejected by the filters for each sensor.
+    pub fn rejected_readings(&self) -> HashMap<String, u64> {
+        self.pipeline.borrow().rejected()
+    }
+
+    /// Flush all logging channels. Any partially filled aggregation window is finished and sent to
+    /// the aggregating sinks before the sinks are flushed.
+    pub fn flush(&self) -> Result<()> {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let aggregate = pipeline.finish();
+
+        let mut result = Ok(());
+        for sink in self.sinks.borrow_mut().iter_mut() {
+            if let Some(aggregate) = &aggregate {
+                if pipeline.aggregates(sink.name()) {
+                    if let Err(err) = sink.log_aggregate(aggregate) {
+                        result = result.and(Err(err));
+                    }
+                }
+            }
+
+            if let Err(err) = sink.flush() {
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+
+    /// Check if the end of the input of the source was reached, such as the end of stdin.
+    pub fn is_finished(&self) -> bool {
+        self.port.borrow().is_finished()
+    }
+
+    /// Get an iterator of readings of the sensors. Each reading waits for sensor data with
+    /// `wait_for_sensor`, and the iterator ends once the logger is stopped or the end of the
+    /// input is reached. Readings are not logged.
+    pub fn readings(&self, retries: u32) -> Readings<'_> {
+        Readings {
+            logger: self,
+            retries,
+        }
+    }
+
+    /// Register a callback receiving every measurement logged, after filtering.
+    pub fn on_reading<F>(&self, callback: F)
+    where
+        F: FnMut(&DhtSensors) + Send + 'static,
+    {
+        self.callbacks.borrow_mut().push(Box::new(callback));
+    }
+
+    /// Use an existing handle to stop the logger, for example one shared with a signal handler.
+    pub fn with_stop_handle(mut self, stop: StopHandle) -> DhtLogger {
+        self.port.get_mut().set_stop_handle(stop.clone());
+        self.stop = stop;
+        self
+    }
+
+    /// Get a handle to stop the logger.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Read and log sensor data until the logger is stopped or the end of the input is reached,
+    /// then flush all logging channels.
+    /// Reloads requested through a `ReloadHandle` are applied between readings.
+    ///
+    /// Args:
+    /// * `retries`: Number of sensor read retries (see `wait_for_sensor` docs) per reading.
+    pub fn run(&self, retries: u32) -> Result<()> {
+        while !self.stop.is_stopped() && !self.is_finished() {
+            if let Some(logger_config) = self.reloads.take() {
+                match self.reload(logger_config.clone()) {
+                    Ok(()) => self.reloads.set_applied(logger_config),
+                    Err(err) => log::error!("Failed to reload, keeping the old config: {}", err),
+                }
+            }
+
+            self.read_sensor_and_log_data(retries);
+        }
+
+        self.flush()
     }
 
     /// Read data from the DHT sensor serial interface and log data to all logging channels.
@@ -290,3 +502,23 @@ impl DhtLogger {
         }
     }
 }
+
+/// Iterator of sensor readings, created by `DhtLogger::readings`.
+pub struct Readings<'a> {
+    logger: &'a DhtLogger,
+    retries: u32,
+}
+
+impl Iterator for Readings<'_> {
+    type Item = Result<DhtSensors>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.logger.wait_for_sensor(self.retries) {
+            Err(err) if err.kind() == ErrorKind::Interrupted && self.logger.stop.is_stopped() => {
+                None
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof && self.logger.is_finished() => None,
+            reading => Some(reading),
+        }
+    }
+}
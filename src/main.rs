@@ -1,42 +1,376 @@
 use std::error::Error;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-use clap::Parser;
-use pretty_env_logger;
+use clap::{Parser, Subcommand};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-use dht_logger::{DhtLogger, DhtLoggerConfig};
+use dht_logger::check;
+use dht_logger::config::{ConfigFormat, ConfigOverrides};
+#[cfg(unix)]
+use dht_logger::systemd::{JournalLogger, Notifier};
+use dht_logger::{DhtLogger, DhtLoggerConfig, ReloadHandle, StopHandle};
 
 const LOOP_RETRIES: u32 = 10;
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
 
 /// Log DHT Sensor readings to various channels.
 #[derive(Parser, Debug)]
 #[clap(version, name = "dht-logger")]
 struct Args {
-    /// Config file containing the DHT logging settings
-    #[clap(short, long)]
-    config: PathBuf,
+    /// Config file containing the DHT logging settings, in YAML, TOML or JSON by extension.
+    /// Optional if the serial port is set by --port, --input or DHT_LOGGER_PORT.
+    #[clap(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Serial port, tcp://host:port or rfc2217://host:port, overriding the config file and
+    /// DHT_LOGGER_PORT
+    #[clap(long, global = true)]
+    port: Option<PathBuf>,
+
+    /// Read device JSON from a file, a FIFO or stdin (-) instead of a serial port, until the end of
+    /// the input
+    #[clap(long, global = true, conflicts_with = "port")]
+    input: Option<PathBuf>,
+
+    /// Baud rate, overriding the config file and DHT_LOGGER_BAUD
+    #[clap(long, global = true)]
+    baud: Option<u32>,
+
+    /// UDP address (IP:PORT or HOST:PORT) to send data to, replacing the addresses of the
+    /// config file and DHT_LOGGER_UDP. May be repeated.
+    #[clap(long, global = true, multiple_occurrences(true))]
+    udp: Vec<String>,
+
+    /// Log sensor readings using log::info!, overriding the config file and DHT_LOGGER_VERBOSE
+    #[clap(short, long, global = true, overrides_with = "no-verbose")]
+    verbose: bool,
+
+    /// Log sensor readings using log::debug!, overriding the config file and DHT_LOGGER_VERBOSE
+    #[clap(long, global = true, overrides_with = "verbose")]
+    no_verbose: bool,
+
+    /// Log to the systemd journal with structured fields instead of stderr. The log level is
+    /// read from RUST_LOG, defaulting to info.
+    #[clap(long)]
+    journald: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Check the config against the device: validate the config, resolve all addresses, open the
+    /// serial port and read one frame. Exits with status 0 if every sensor returned a valid
+    /// reading, 1 on sensor errors, 2 on an invalid config, 3 on unresolvable addresses, 4 if the
+    /// port cannot be opened and 5 if no frame could be read.
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective config, including overrides and default values
+    Print {
+        /// Output format: yaml, toml or json
+        #[clap(long, default_value = "yaml")]
+        format: ConfigFormat,
+    },
+}
+
+/// Where the config is loaded from: an optional config file, overridden by the environment and
+/// the command line.
+#[derive(Clone, Debug)]
+struct ConfigSource {
+    path: Option<PathBuf>,
+    overrides: ConfigOverrides,
+}
+
+impl ConfigSource {
+    fn new(args: &Args) -> Result<ConfigSource, Box<dyn Error>> {
+        let mut cli = ConfigOverrides {
+            port: args.input.clone().or_else(|| args.port.clone()),
+            baud: args.baud,
+            ..Default::default()
+        };
+        if !args.udp.is_empty() {
+            cli.set_udp(args.udp.iter().map(String::as_str));
+        }
+        if args.verbose || args.no_verbose {
+            cli.logger_config.insert(
+                String::from("verbose"),
+                serde_json::Value::Bool(args.verbose),
+            );
+        }
+
+        Ok(ConfigSource {
+            path: args.config.clone(),
+            overrides: ConfigOverrides::from_env()?.merge(cli),
+        })
+    }
+
+    fn load(&self) -> Result<DhtLoggerConfig, Box<dyn Error>> {
+        let config = match &self.path {
+            Some(path) => Some(DhtLoggerConfig::load(path)?),
+            None => None,
+        };
+        Ok(self.overrides.clone().apply(config)?)
+    }
+}
+
+/// Check a config against the device, returning the exit status of the check.
+fn check(source: &ConfigSource) -> i32 {
+    let config = match source.load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid config: {}", err);
+            return 2;
+        }
+    };
+    if let Err(err) = config.validate() {
+        eprintln!("Invalid config: {}", err);
+        return 2;
+    }
+
+    match config.resolve_addresses() {
+        Ok(addrs) => {
+            for addr in addrs {
+                println!("Resolved address: {}", addr);
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to resolve addresses: {}", err);
+            return 3;
+        }
+    }
+
+    let source = match config.open_source() {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Failed to open port {}: {}", config.port.display(), err);
+            return 4;
+        }
+    };
+
+    match check::read_frame(&config, source, LOOP_RETRIES) {
+        Ok(report) => {
+            print!("{}", report);
+            if report.is_ok() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to read a frame: {}", err);
+            5
+        }
+    }
+}
+
+/// Load the config again and request the logger to reload it, unless the logger config is the
+/// one applied last: the one of the last successful reload, or else the one of `initial`, which
+/// the logger was started with. A failed reload is retried on the next request. The serial port
+/// is never reopened, so changes of the serial port settings are ignored.
+fn request_reload(source: &ConfigSource, initial: &DhtLoggerConfig, reload: &ReloadHandle) {
+    let config = match source.load() {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("Failed to read config, keeping the old one: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = config.validate() {
+        log::error!("Invalid config, keeping the old one: {}", err);
+        return;
+    }
 
+    if config.port != initial.port || config.baud != initial.baud || config.serial != initial.serial
+    {
+        log::warn!("Serial port settings cannot be reloaded, restart to apply them");
+    }
+    let applied = reload
+        .applied()
+        .unwrap_or_else(|| initial.logger_config.clone());
+    if config.logger_config == applied {
+        log::debug!("Logger config is unchanged, not reloading");
+        return;
+    }
+    log::info!("Reloading config");
+    reload.reload(config.logger_config);
+}
+
+/// Stop the logger on the first SIGTERM or SIGINT, and exit immediately on the second one.
+/// Reload the config on SIGHUP.
+fn handle_signals(
+    stop: StopHandle,
+    reload: ReloadHandle,
+    source: ConfigSource,
+    initial: Arc<DhtLoggerConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::Builder::new()
+        .name(String::from("signals"))
+        .spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGHUP {
+                    request_reload(&source, &initial, &reload);
+                    continue;
+                }
+
+                if stop.is_stopped() {
+                    log::warn!("Received signal {} again, exiting immediately", signal);
+                    std::process::exit(1);
+                }
+
+                log::info!("Received signal {}, shutting down", signal);
+                #[cfg(unix)]
+                if let Ok(Some(notifier)) = Notifier::from_env() {
+                    let _ = notifier.stopping();
+                }
+                stop.stop();
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Reload the config whenever the modification time of the config file changes.
+fn watch_config(
+    reload: ReloadHandle,
+    source: ConfigSource,
+    initial: Arc<DhtLoggerConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let path = match source.path.clone() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let mut last_modified = modified(&path).ok();
+    thread::Builder::new()
+        .name(String::from("config-watch"))
+        .spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            let modified = modified(&path).ok();
+            if modified.is_some() && modified != last_modified {
+                last_modified = modified;
+                request_reload(&source, &initial, &reload);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Log to the systemd journal at the level from RUST_LOG.
+#[cfg(unix)]
+fn init_journald() -> Result<(), Box<dyn Error>> {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    JournalLogger::init(level)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn init_journald() -> Result<(), Box<dyn Error>> {
+    Err("--journald is only supported on unix".into())
+}
+
+/// Report readiness and ping the watchdog only while sensor data is arriving.
+#[cfg(unix)]
+fn notify_systemd(logger: &DhtLogger) -> Result<(), Box<dyn Error>> {
+    if let Some(mut notifier) = Notifier::from_env()? {
+        logger.on_reading(move |measurement| {
+            if measurement.data.is_empty() {
+                return;
+            }
+            if let Err(err) = notifier.reading() {
+                log::warn!("Failed to notify systemd: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_systemd(_logger: &DhtLogger) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
-    let config = DhtLoggerConfig::load_yaml(&args.config);
+    if args.journald {
+        init_journald()?;
+    } else {
+        pretty_env_logger::init();
+    }
 
-    log::info!("Waiting for serial port: {}", config.port.to_str().unwrap());
-    while !config.port.exists() {
-        thread::sleep(Duration::from_secs(1));
+    let source = ConfigSource::new(&args)?;
+    if let Some(Command::Check) = args.command {
+        std::process::exit(check(&source));
+    }
+
+    let config = source.load()?;
+    if let Some(Command::Config {
+        command: ConfigCommand::Print { format },
+    }) = args.command
+    {
+        print!("{}", format.to_string(&config.with_defaults()?)?);
+        return Ok(());
     }
 
-    let logger = DhtLogger::from_config(&config);
+    let stop = StopHandle::default();
+    let reload = ReloadHandle::default();
+    let initial = Arc::new(config.clone());
+    handle_signals(
+        stop.clone(),
+        reload.clone(),
+        source.clone(),
+        initial.clone(),
+    )?;
+    watch_config(reload.clone(), source, initial)?;
+
+    // Only errors of opening the port and the sinks are retried, an invalid config is not.
+    config.validate()?;
+    log::info!("Waiting for port: {}", config.port.display());
+    let mut last_error = None;
+    let logger = loop {
+        match DhtLogger::open(&config) {
+            Ok(logger) => break logger,
+            Err(err) => {
+                let reason = err.to_string();
+                if last_error.as_ref() != Some(&reason) {
+                    log::warn!("Failed to open the logger, retrying: {}", reason);
+                } else {
+                    log::trace!("{}", reason);
+                }
+                last_error = Some(reason);
+            }
+        }
+        if stop.is_stopped() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(1));
+    };
+    let logger = logger.with_stop_handle(stop).with_reload_handle(reload);
     match logger.port() {
         Some(port) => log::info!("Listening for data on port: {}", port.to_str().unwrap()),
         None => log::info!("Listening for data..."),
     }
 
-    loop {
-        logger.read_sensor_and_log_data(LOOP_RETRIES);
-    }
+    notify_systemd(&logger)?;
+
+    logger.run(LOOP_RETRIES)?;
+    log::info!("Flushed all sinks, exiting");
+    Ok(())
 }
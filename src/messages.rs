@@ -1,50 +1,171 @@
 //! Serializable messages representing DHT sensor data.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Error, ErrorKind};
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 
+use super::units::TemperatureUnit;
+use super::validation::heat_index;
 use super::Result;
 
 /// Serde JSON from the DHT sensor over serial.
+///
+/// JSON has no representation of NaN, so `null` values are deserialized as NaN. The heat index is
+/// optional, and fields other than `t`, `h` and `hi` are collected in `extra`, such as the
+/// pressure of a BME280 or the CO₂ concentration of an SCD30.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DhtDataRaw {
+    #[serde(deserialize_with = "nan_if_null")]
     pub t: f32,
+    #[serde(deserialize_with = "nan_if_null")]
     pub h: f32,
-    pub hi: f32,
+    #[serde(
+        default,
+        deserialize_with = "some_nan_if_null",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub hi: Option<f32>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
-/// A single reading for a DHT sensor.
-#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+fn nan_if_null<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<f32, D::Error> {
+    Ok(Option::<f32>::deserialize(deserializer)?.unwrap_or(f32::NAN))
+}
+
+fn some_nan_if_null<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<f32>, D::Error> {
+    nan_if_null(deserializer).map(Some)
+}
+
+fn map_nan_if_null<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, f32>, D::Error> {
+    let values = BTreeMap::<String, Option<f32>>::deserialize(deserializer)?;
+    Ok(values
+        .into_iter()
+        .map(|(name, value)| (name, value.unwrap_or(f32::NAN)))
+        .collect())
+}
+
+type ExtraColumns = BTreeMap<String, Vec<(usize, f32)>>;
+
+fn extra_nan_if_null<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<ExtraColumns, D::Error> {
+    let columns = BTreeMap::<String, Vec<(usize, Option<f32>)>>::deserialize(deserializer)?;
+    Ok(columns
+        .into_iter()
+        .map(|(name, values)| {
+            let values = values
+                .into_iter()
+                .map(|(i, value)| (i, value.unwrap_or(f32::NAN)))
+                .collect();
+            (name, values)
+        })
+        .collect())
+}
+
+impl DhtDataRaw {
+    /// Convert the raw JSON to SensorData with full field names. A missing heat index is computed
+    /// from the temperature and humidity, which are in `unit`. Extra fields that are numbers, or
+    /// `null` for NaN, are kept as extra measurements, and any other extra fields are dropped.
+    pub fn into_data(self, unit: TemperatureUnit) -> SensorData {
+        let heat_index = self.hi.unwrap_or_else(|| {
+            let celsius = unit.convert(self.t, TemperatureUnit::Celsius);
+            TemperatureUnit::Celsius.convert(heat_index(celsius, self.h), unit)
+        });
+        let extra = self
+            .extra
+            .into_iter()
+            .filter_map(|(name, value)| match value {
+                Value::Number(number) => Some((name, number.as_f64()? as f32)),
+                Value::Null => Some((name, f32::NAN)),
+                _ => None,
+            })
+            .collect();
+
+        SensorData {
+            temperature: self.t,
+            humidity: self.h,
+            heat_index,
+            extra,
+        }
+    }
+}
+
+/// A single reading for a DHT sensor, with any extra measurements of the sensor by name.
+///
+/// Extra measurements may be NaN, which JSON serializes as `null` and which is deserialized as NaN
+/// again.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct SensorData {
     pub temperature: f32,
     pub humidity: f32,
     pub heat_index: f32,
+    #[serde(
+        default,
+        deserialize_with = "map_nan_if_null",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub extra: BTreeMap<String, f32>,
 }
 
-/// Convert the RAW Json to SensorData so it can be re-serialized with full field names.
-impl From<DhtDataRaw> for SensorData {
-    fn from(data: DhtDataRaw) -> Self {
+impl SensorData {
+    /// Convert the temperature and heat index from one unit to another. Extra measurements are
+    /// not converted.
+    pub fn to_unit(&self, from: TemperatureUnit, to: TemperatureUnit) -> SensorData {
         SensorData {
-            temperature: data.t,
-            humidity: data.h,
-            heat_index: data.hi,
+            temperature: from.convert(self.temperature, to),
+            humidity: self.humidity,
+            heat_index: from.convert(self.heat_index, to),
+            extra: self.extra.clone(),
         }
     }
 }
 
+/// Convert the RAW Json to SensorData so it can be re-serialized with full field names. The
+/// readings are assumed to be in degrees Celsius (see `DhtDataRaw::into_data`).
+impl From<DhtDataRaw> for SensorData {
+    fn from(data: DhtDataRaw) -> Self {
+        data.into_data(TemperatureUnit::Celsius)
+    }
+}
+
 /// Container of measurements from all DHT sensors in one reading.
 ///
+/// Sensors that failed to produce a valid reading are listed in `errors` instead of `data`.
+///
 /// The JSON serialization is not compact. For smaller JSON messages, use `DhtSensorsSerde`.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DhtSensors {
     pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub unit: TemperatureUnit,
     pub data: HashMap<String, SensorData>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub errors: HashMap<String, String>,
 }
 
 impl DhtSensors {
+    /// Convert the temperatures of all sensors to another unit.
+    pub fn to_unit(&self, unit: TemperatureUnit) -> DhtSensors {
+        DhtSensors {
+            timestamp: self.timestamp,
+            unit,
+            data: self
+                .data
+                .iter()
+                .map(|(label, data)| (label.clone(), data.to_unit(self.unit, unit)))
+                .collect(),
+            errors: self.errors.clone(),
+        }
+    }
+
     /// Decode a `DntSensorsSerde` struct into DhtSensors.
     ///
     /// If not all hashmaps in DhtSensorsPacked have
@@ -62,21 +183,37 @@ impl DhtSensors {
             ));
         }
 
+        let mut extra = vec![BTreeMap::new(); data.o.len()];
+        for (name, values) in data.x {
+            for (i, value) in values {
+                let sensor_extra = extra.get_mut(i).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        "extra measurement index out of range in serde data",
+                    )
+                })?;
+                sensor_extra.insert(name.clone(), value);
+            }
+        }
+
         let mut sensor_data = HashMap::new();
-        for (i, key) in data.o.iter().enumerate() {
+        for ((i, key), extra) in data.o.into_iter().enumerate().zip(extra) {
             sensor_data.insert(
-                key.clone(),
+                key,
                 SensorData {
                     temperature: data.t[i],
                     humidity: data.h[i],
                     heat_index: data.hi[i],
+                    extra,
                 },
             );
         }
 
         Ok(DhtSensors {
             timestamp: data.ts,
+            unit: data.u,
             data: sensor_data,
+            errors: HashMap::new(),
         })
     }
 }
@@ -84,43 +221,150 @@ impl DhtSensors {
 /// A more compactly serialized verson of DhtSensors for serializing via JSON
 ///
 /// This is not intended on being human-readable. For human-readability, use `DhtSensors` instead.
+/// Extra measurements are stored in `x` with a list of `[index, value]` pairs per name, where the
+/// index is the position of the sensor in `o`. Sensors without the measurement are left out, and
+/// NaN values are serialized as `null` in JSON.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DhtSensorsSerde {
     pub ts: DateTime<Utc>,
+    #[serde(default)]
+    pub u: TemperatureUnit,
     pub o: Vec<String>,
     pub t: Vec<f32>,
     pub h: Vec<f32>,
     pub hi: Vec<f32>,
+    #[serde(
+        default,
+        deserialize_with = "extra_nan_if_null",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub x: ExtraColumns,
 }
 
 impl From<DhtSensors> for DhtSensorsSerde {
     fn from(data: DhtSensors) -> DhtSensorsSerde {
+        DhtSensorsSerde::from(&data)
+    }
+}
+
+impl From<&DhtSensors> for DhtSensorsSerde {
+    fn from(data: &DhtSensors) -> DhtSensorsSerde {
         let timestamp = data.timestamp;
         let mut order = Vec::new();
         let mut temperature = Vec::new();
         let mut humidity = Vec::new();
         let mut heat_index = Vec::new();
+        let mut extra = ExtraColumns::new();
 
-        for (key, value) in data.data.iter() {
+        for (i, (key, value)) in data.data.iter().enumerate() {
             order.push(key.clone());
             temperature.push(value.temperature);
             humidity.push(value.humidity);
             heat_index.push(value.heat_index);
+            for (name, &measurement) in value.extra.iter() {
+                extra
+                    .entry(name.clone())
+                    .or_default()
+                    .push((i, measurement));
+            }
         }
 
         DhtSensorsSerde {
             ts: timestamp,
+            u: data.unit,
             o: order,
             t: temperature,
             h: humidity,
             hi: heat_index,
+            x: extra,
+        }
+    }
+}
+
+/// Summary statistics of a single field of a DHT sensor over an aggregation window.
+///
+/// The mean and standard deviation of a field with NaN readings are NaN, which JSON serializes as
+/// `null` and which is deserialized as NaN again.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FieldAggregate {
+    pub count: usize,
+    #[serde(deserialize_with = "nan_if_null")]
+    pub min: f32,
+    #[serde(deserialize_with = "nan_if_null")]
+    pub max: f32,
+    #[serde(deserialize_with = "nan_if_null")]
+    pub mean: f32,
+    #[serde(deserialize_with = "nan_if_null")]
+    pub stddev: f32,
+}
+
+impl FieldAggregate {
+    /// Convert the statistics of a temperature field from one unit to another.
+    pub fn to_unit(&self, from: TemperatureUnit, to: TemperatureUnit) -> FieldAggregate {
+        FieldAggregate {
+            count: self.count,
+            min: from.convert(self.min, to),
+            max: from.convert(self.max, to),
+            mean: from.convert(self.mean, to),
+            stddev: from.convert_difference(self.stddev, to),
+        }
+    }
+}
+
+/// Aggregated readings of a single DHT sensor over an aggregation window.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SensorAggregate {
+    pub temperature: FieldAggregate,
+    pub humidity: FieldAggregate,
+    pub heat_index: FieldAggregate,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, FieldAggregate>,
+}
+
+impl SensorAggregate {
+    /// Convert the temperature and heat index statistics from one unit to another. Statistics of
+    /// extra measurements are not converted.
+    pub fn to_unit(&self, from: TemperatureUnit, to: TemperatureUnit) -> SensorAggregate {
+        SensorAggregate {
+            temperature: self.temperature.to_unit(from, to),
+            humidity: self.humidity,
+            heat_index: self.heat_index.to_unit(from, to),
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+/// Container of aggregated measurements from all DHT sensors over one aggregation window.
+///
+/// The window covers all readings with `start <= timestamp < end`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AggregateSensors {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub unit: TemperatureUnit,
+    pub data: HashMap<String, SensorAggregate>,
+}
+
+impl AggregateSensors {
+    /// Convert the temperature statistics of all sensors to another unit.
+    pub fn to_unit(&self, unit: TemperatureUnit) -> AggregateSensors {
+        AggregateSensors {
+            start: self.start,
+            end: self.end,
+            unit,
+            data: self
+                .data
+                .iter()
+                .map(|(label, data)| (label.clone(), data.to_unit(self.unit, unit)))
+                .collect(),
         }
     }
 }
 
-union DhtDataUnion<'a> {
-    error: &'a str,
-    data: SensorData,
+enum MeasurementData<'a> {
+    Error(&'a str),
+    Data(SensorData),
 }
 
 /// Data container for a DHT sensor measurement that contains either an error or data.
@@ -132,6 +376,7 @@ union DhtDataUnion<'a> {
 ///     temperature: 0.0,
 ///     humidity: 0.0,
 ///     heat_index: 0.0,
+///     ..Default::default()
 /// };
 ///
 /// // Create a measurement containing an error
@@ -141,14 +386,13 @@ union DhtDataUnion<'a> {
 /// assert_eq!(measurement.get_error().unwrap(), error);
 ///
 /// // Create a measurement containing data
-/// let measurement = Measurement::new(Some(data), None);
+/// let measurement = Measurement::new(Some(data.clone()), None);
 /// assert!(measurement.get_data().is_some());
 /// assert!(measurement.get_error().is_none());
 /// assert_eq!(measurement.get_data().unwrap(), data);
 /// ```
 pub struct Measurement<'a> {
-    error: bool,
-    data: DhtDataUnion<'a>,
+    data: MeasurementData<'a>,
 }
 
 impl<'a> Measurement<'a> {
@@ -157,60 +401,39 @@ impl<'a> Measurement<'a> {
     /// Args:
     /// * `data`: Sensor data from one DHT sensor.
     /// * `error`: Error indicating a failure to read a DHT sensor.
-    pub fn new(data: Option<SensorData>, error: Option<&'a str>) -> Measurement {
-        if (data.is_some() && error.is_some()) || (data.is_none() && error.is_none()) {
-            panic!("Exactly one of data or error must be a Some type.");
-        }
-
-        if let Some(data) = data {
-            return Measurement {
-                error: false,
-                data: DhtDataUnion { data },
-            };
-        }
-
-        if let Some(error) = error {
-            return Measurement {
-                error: true,
-                data: DhtDataUnion { error },
-            };
-        }
-
-        // This should never happen
-        Measurement {
-            error: true,
-            data: DhtDataUnion {
-                error: "initialization error",
-            },
-        }
+    pub fn new(data: Option<SensorData>, error: Option<&'a str>) -> Measurement<'a> {
+        let data = match (data, error) {
+            (Some(data), None) => MeasurementData::Data(data),
+            (None, Some(error)) => MeasurementData::Error(error),
+            _ => panic!("Exactly one of data or error must be a Some type."),
+        };
+        Measurement { data }
     }
 
     /// Get the data contained by the measurement, if it exists.
     pub fn get_data(&self) -> Option<SensorData> {
-        if self.has_data() {
-            unsafe { Some(self.data.data) }
-        } else {
-            None
+        match &self.data {
+            MeasurementData::Data(data) => Some(data.clone()),
+            MeasurementData::Error(_) => None,
         }
     }
 
     /// Get the error contained by the measurement, if it exists.
     pub fn get_error(&self) -> Option<&'a str> {
-        if self.has_error() {
-            unsafe { Some(self.data.error) }
-        } else {
-            None
+        match self.data {
+            MeasurementData::Error(error) => Some(error),
+            MeasurementData::Data(_) => None,
         }
     }
 
     /// Check if the measurement has data.
     pub fn has_data(&self) -> bool {
-        !self.error
+        matches!(self.data, MeasurementData::Data(_))
     }
 
     /// Check if the measurement has an error.
     pub fn has_error(&self) -> bool {
-        self.error
+        !self.has_data()
     }
 }
 
@@ -229,11 +452,7 @@ mod tests {
     #[should_panic]
     fn test_measurement_new_both_some() {
         let error = "test";
-        let data = SensorData {
-            temperature: 0.0,
-            humidity: 0.0,
-            heat_index: 0.0,
-        };
+        let data = SensorData::default();
         Measurement::new(Some(data), Some(error));
     }
 
@@ -243,12 +462,71 @@ mod tests {
         let raw = DhtDataRaw {
             t: 21.3,
             h: 52.7,
-            hi: 22.8,
+            hi: Some(22.8),
+            extra: BTreeMap::new(),
         };
 
         let data = SensorData::from(raw.clone());
         assert_eq!(raw.t, data.temperature);
         assert_eq!(raw.h, data.humidity);
-        assert_eq!(raw.hi, data.heat_index);
+        assert_eq!(raw.hi, Some(data.heat_index));
+    }
+
+    // Test that null values from the device are deserialized as NaN
+    #[test]
+    fn test_raw_null_is_nan() {
+        let raw: DhtDataRaw =
+            serde_json::from_str(r#"{"t": null, "h": 50.0, "hi": null}"#).unwrap();
+        assert!(raw.t.is_nan());
+        assert_eq!(raw.h, 50.0);
+        assert!(raw.hi.unwrap().is_nan());
+    }
+
+    // Test that extra numeric fields are kept through the compact serialization and that a
+    // missing heat index is computed
+    #[test]
+    fn test_extra_fields() {
+        let raw: DhtDataRaw =
+            serde_json::from_str(r#"{"t": 68.0, "h": 50.0, "p": 1013.2, "fw": "1.2"}"#).unwrap();
+        let data = raw.into_data(TemperatureUnit::Fahrenheit);
+        let expected =
+            TemperatureUnit::Celsius.convert(heat_index(20.0, 50.0), TemperatureUnit::Fahrenheit);
+        assert!((data.heat_index - expected).abs() < 1e-3);
+        assert_eq!(data.extra, BTreeMap::from([(String::from("p"), 1013.2)]));
+
+        let mut sensors = DhtSensors {
+            timestamp: Utc::now(),
+            unit: TemperatureUnit::Fahrenheit,
+            data: HashMap::from([
+                (String::from("a"), data),
+                (String::from("b"), SensorData::default()),
+            ]),
+            errors: HashMap::new(),
+        };
+        let serde: DhtSensorsSerde =
+            serde_json::from_slice(&serde_json::to_vec(&DhtSensorsSerde::from(&sensors)).unwrap())
+                .unwrap();
+        assert_eq!(serde.x["p"].len(), 1);
+        let decoded = DhtSensors::from_serde(serde).unwrap();
+        assert_eq!(decoded.data, sensors.data);
+
+        // NaN extra measurements survive JSON as null, and are not dropped as missing.
+        sensors
+            .data
+            .get_mut("b")
+            .unwrap()
+            .extra
+            .insert(String::from("co2"), f32::NAN);
+        let serde: DhtSensorsSerde =
+            serde_json::from_slice(&serde_json::to_vec(&DhtSensorsSerde::from(&sensors)).unwrap())
+                .unwrap();
+        let decoded = DhtSensors::from_serde(serde).unwrap();
+        assert!(decoded.data["b"].extra["co2"].is_nan());
+        assert!(!decoded.data["a"].extra.contains_key("co2"));
+
+        let mut serde = DhtSensorsSerde::from(&sensors);
+        serde.x.get_mut("co2").unwrap().push((2, 400.0));
+        let err = DhtSensors::from_serde(serde).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
     }
 }
@@ -0,0 +1,146 @@
+//! Parsing of the JSON frames sent by a DHT logger device.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use super::messages::*;
+use super::sensors::SensorRegistry;
+use super::units::TemperatureUnit;
+use super::validation;
+use super::Result;
+
+/// Parser of the frames of a device, normalizing readings to Celsius from the `input_unit` of
+/// the device and validating them against the sensor registry.
+pub struct FrameParser {
+    input_unit: TemperatureUnit,
+    registry: Arc<SensorRegistry>,
+}
+
+impl FrameParser {
+    /// Create a frame parser for readings sent in `input_unit`.
+    pub fn new(input_unit: TemperatureUnit, registry: Arc<SensorRegistry>) -> FrameParser {
+        FrameParser {
+            input_unit,
+            registry,
+        }
+    }
+
+    /// Create a frame parser from the `input_unit` logger config option. Returns an
+    /// `InvalidData` error if it cannot be parsed.
+    pub fn from_logger_config(
+        logger_config: &HashMap<String, Value>,
+        registry: Arc<SensorRegistry>,
+    ) -> Result<FrameParser> {
+        let input_unit = match logger_config.get("input_unit") {
+            Some(unit) => serde_json::from_value(unit.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.input_unit: {}", err),
+                )
+            })?,
+            None => TemperatureUnit::Celsius,
+        };
+
+        Ok(FrameParser::new(input_unit, registry))
+    }
+
+    /// Get the registry of sensor metadata.
+    pub fn registry(&self) -> &Arc<SensorRegistry> {
+        &self.registry
+    }
+
+    /// Parse a frame received at `timestamp`. Temperatures are returned in degrees Celsius.
+    ///
+    /// Sensors reporting an error, or a reading that is invalid for the model of the sensor, are
+    /// listed in the `errors` of the result. Readings of unknown sensors are dropped if the
+    /// registry is configured to reject them.
+    pub fn parse(&self, buffer: &[u8], timestamp: DateTime<Utc>) -> Result<DhtSensors> {
+        if let Ok(buffer) = std::str::from_utf8(buffer) {
+            log::trace!("got bytes: {}", buffer);
+        }
+        let raw = match serde_json::from_slice::<Value>(buffer)? {
+            Value::Object(map) => map,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "DHT logger data must be a JSON mapping",
+                ))
+            }
+        };
+
+        let mut sensors = HashMap::new();
+        let mut errors = HashMap::new();
+        for (key, value) in raw.iter() {
+            let invalid;
+            let error_kind;
+            let measurement = match value {
+                Value::Object(value) => match value.get("e") {
+                    Some(Value::String(error)) => {
+                        error_kind = "device";
+                        Measurement::new(None, Some(error))
+                    }
+                    Some(error) => {
+                        error_kind = "parse";
+                        invalid = format!("error value must be a string, got value: {}", error);
+                        Measurement::new(None, Some(&invalid))
+                    }
+                    None => {
+                        match serde_json::from_value::<DhtDataRaw>(Value::Object(value.clone())) {
+                            Ok(raw) => {
+                                let data = raw.into_data(self.input_unit);
+                                let model =
+                                    self.registry.get(key).and_then(|metadata| metadata.model);
+                                error_kind = "invalid";
+                                match validation::validate(&data, self.input_unit, model) {
+                                    Ok(()) => Measurement::new(
+                                        Some(
+                                            data.to_unit(self.input_unit, TemperatureUnit::Celsius),
+                                        ),
+                                        None,
+                                    ),
+                                    Err(err) => {
+                                        invalid = err;
+                                        Measurement::new(None, Some(&invalid))
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                error_kind = "parse";
+                                invalid = format!("invalid sensor data: {}", err);
+                                Measurement::new(None, Some(&invalid))
+                            }
+                        }
+                    }
+                },
+                _ => {
+                    error_kind = "parse";
+                    invalid = format!("sensor value must be a JSON mapping, got value: {}", value);
+                    Measurement::new(None, Some(&invalid))
+                }
+            };
+
+            if let Some(error) = measurement.get_error() {
+                log::warn!(
+                    sensor = key.as_str(), error_kind = error_kind;
+                    "Error reading '{}' sensor: {}", key, error
+                );
+                errors.insert(String::from(key), String::from(error));
+                continue;
+            }
+
+            let data = measurement.get_data().unwrap();
+            sensors.insert(String::from(key), data);
+        }
+
+        Ok(self.registry.check(DhtSensors {
+            timestamp,
+            unit: TemperatureUnit::Celsius,
+            data: sensors,
+            errors,
+        }))
+    }
+}
@@ -0,0 +1,112 @@
+//! Processing of measurements between reading them and handing them to the sinks.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use super::aggregate::{AggregateConfig, Aggregator};
+use super::filters::{FiltersConfig, SensorFilters};
+use super::messages::*;
+use super::Result;
+
+/// Filters and aggregation applied to every measurement, configured by the `filters` and
+/// `aggregate` logger config options.
+#[derive(Default)]
+pub struct Pipeline {
+    filters: Option<SensorFilters>,
+    aggregator: Option<Aggregator>,
+    aggregate_sinks: HashSet<String>,
+}
+
+impl Pipeline {
+    /// Create a pipeline from the logger config. Returns an `InvalidData` error if the filters or
+    /// the aggregation are invalid, or if an aggregating sink is not one of `sink_names`.
+    pub fn from_logger_config(
+        logger_config: &HashMap<String, Value>,
+        sink_names: &[&str],
+    ) -> Result<Self> {
+        let filters = match logger_config.get("filters") {
+            Some(filters) => {
+                let filters: FiltersConfig =
+                    serde_json::from_value(filters.clone()).map_err(|err| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Failed to parse logger.filters: {}", err),
+                        )
+                    })?;
+                Some(SensorFilters::new(filters)?)
+            }
+            None => None,
+        };
+
+        let aggregate: Option<AggregateConfig> = match logger_config.get("aggregate") {
+            Some(aggregate) => Some(serde_json::from_value(aggregate.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.aggregate: {}", err),
+                )
+            })?),
+            None => None,
+        };
+        let aggregator = match aggregate.as_ref() {
+            Some(aggregate) => Some(Aggregator::new(Duration::from_secs(aggregate.interval))?),
+            None => None,
+        };
+        let aggregate_sinks: HashSet<String> = aggregate
+            .map(|aggregate| aggregate.sinks.into_iter().collect())
+            .unwrap_or_default();
+        for name in aggregate_sinks.iter() {
+            if !sink_names.contains(&name.as_str()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("logger.aggregate refers to unconfigured sink: {}", name),
+                ));
+            }
+        }
+
+        Ok(Pipeline {
+            filters,
+            aggregator,
+            aggregate_sinks,
+        })
+    }
+
+    /// Filter a measurement and push it to the aggregator. Returns the filtered measurement, and
+    /// the aggregate of the previous window if the measurement completes it.
+    pub fn process(&mut self, measurement: DhtSensors) -> (DhtSensors, Option<AggregateSensors>) {
+        let measurement = match self.filters.as_mut() {
+            Some(filters) => filters.apply(measurement),
+            None => measurement,
+        };
+
+        let aggregate = match self.aggregator.as_mut() {
+            Some(aggregator) => aggregator.push(&measurement),
+            None => None,
+        };
+
+        (measurement, aggregate)
+    }
+
+    /// Finish any partially filled aggregation window.
+    pub fn finish(&mut self) -> Option<AggregateSensors> {
+        match self.aggregator.as_mut() {
+            Some(aggregator) => aggregator.finish(),
+            None => None,
+        }
+    }
+
+    /// Check if a sink receives aggregates instead of raw measurements.
+    pub fn aggregates(&self, sink: &str) -> bool {
+        self.aggregate_sinks.contains(sink)
+    }
+
+    /// Get the number of readings rejected by the filters for each sensor.
+    pub fn rejected(&self) -> HashMap<String, u64> {
+        match self.filters.as_ref() {
+            Some(filters) => filters.rejected().clone(),
+            None => HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Persistent store-and-forward queue for sinks that may be temporarily unavailable.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::messages::*;
+use super::sinks::{Sink, SinkMetrics};
+use super::Result;
+
+/// Default size limit of a disk queue, 16 MiB.
+pub const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+fn default_max_bytes() -> u64 {
+    DEFAULT_MAX_BYTES
+}
+
+/// Configuration of a disk queue in front of a sink.
+///
+/// Example configuration YAML (inside of `sinks.<name>`):
+/// ```yaml
+/// queue:
+///   path: /var/lib/dht-logger/udp.queue
+///   max_bytes: 16777216
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueueConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+/// A persistent FIFO queue of newline-delimited records stored in a file.
+///
+/// Records are appended to the data file, and the offset of the oldest record is stored in a
+/// separate `.head` file next to it, so that a queue survives restarts. When the queue exceeds
+/// its size limit, the oldest records are dropped.
+///
+/// Appended records are synced to disk before `push` returns. Removing records only moves the
+/// head in memory until `sync` is called or the queue is dropped, so records removed since then
+/// are delivered again after a crash.
+pub struct DiskQueue {
+    path: PathBuf,
+    file: File,
+    head_file: File,
+    head: u64,
+    synced_head: u64,
+    lengths: VecDeque<u64>,
+    bytes: u64,
+    max_bytes: u64,
+    dropped: u64,
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head_path = path.as_os_str().to_owned();
+    head_path.push(".head");
+    PathBuf::from(head_path)
+}
+
+impl DiskQueue {
+    /// Open a disk queue, creating it if it does not exist yet.
+    pub fn open(path: &Path, max_bytes: u64) -> Result<DiskQueue> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut head_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(head_path(path))?;
+        let mut head = String::new();
+        head_file.read_to_string(&mut head)?;
+        let head = head.trim().parse().unwrap_or(0).min(file_len);
+
+        // Index the queued records, discarding a partially written record at the end.
+        file.seek(SeekFrom::Start(head))?;
+        let mut lengths = VecDeque::new();
+        let mut reader = BufReader::new(&mut file);
+        let mut record = Vec::new();
+        let mut end = head;
+        loop {
+            record.clear();
+            let n_bytes = reader.read_until(b'\n', &mut record)? as u64;
+            if n_bytes == 0 || record.last() != Some(&b'\n') {
+                break;
+            }
+            lengths.push_back(n_bytes);
+            end += n_bytes;
+        }
+        file.set_len(end)?;
+
+        let mut queue = DiskQueue {
+            path: path.to_path_buf(),
+            file,
+            head_file,
+            head,
+            synced_head: head,
+            bytes: end - head,
+            lengths,
+            max_bytes,
+            dropped: 0,
+        };
+        if queue.is_empty() {
+            queue.clear()?;
+        }
+
+        Ok(queue)
+    }
+
+    /// Get the path of the data file of the queue.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the number of queued records.
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// Check if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    /// Get the total size of the queued records in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Get the number of records dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Append a record to the queue, dropping the oldest records if the queue is full. Records
+    /// must not contain newlines.
+    pub fn push(&mut self, record: &[u8]) -> Result<()> {
+        if record.contains(&b'\n') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "queued records must not contain newlines",
+            ));
+        }
+
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(record)?;
+        self.file.write_all(b"\n")?;
+        self.file.sync_data()?;
+        self.lengths.push_back(record.len() as u64 + 1);
+        self.bytes += record.len() as u64 + 1;
+
+        while self.bytes > self.max_bytes && self.len() > 1 {
+            self.pop()?;
+            self.dropped += 1;
+            log::warn!(
+                "Queue {} is full, dropped oldest record ({} dropped)",
+                self.path.display(),
+                self.dropped
+            );
+        }
+
+        self.sync()
+    }
+
+    /// Get the oldest record in the queue without removing it.
+    pub fn front(&mut self) -> Result<Option<Vec<u8>>> {
+        let length = match self.lengths.front() {
+            Some(length) => *length,
+            None => return Ok(None),
+        };
+
+        let mut record = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(self.head))?;
+        self.file.read_exact(&mut record)?;
+        record.pop();
+        Ok(Some(record))
+    }
+
+    /// Remove the oldest record from the queue. The new head is stored by `sync`.
+    pub fn pop(&mut self) -> Result<()> {
+        let length = match self.lengths.pop_front() {
+            Some(length) => length,
+            None => return Ok(()),
+        };
+
+        self.head += length;
+        self.bytes -= length;
+        if self.is_empty() {
+            self.clear()
+        } else if self.head > self.max_bytes {
+            self.compact()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Store the offset of the oldest record on disk, if it changed since the last sync.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.head == self.synced_head {
+            return Ok(());
+        }
+
+        // The offset has a fixed width, so that it is overwritten in place without truncating
+        // the head file first.
+        self.head_file.seek(SeekFrom::Start(0))?;
+        self.head_file
+            .write_all(format!("{:020}\n", self.head).as_bytes())?;
+        self.head_file.sync_data()?;
+        self.synced_head = self.head;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.head = 0;
+        self.synced_head = u64::MAX;
+        self.sync()
+    }
+
+    /// Move the queued records to the start of the data file.
+    fn compact(&mut self) -> Result<()> {
+        let mut records = Vec::new();
+        self.file.seek(SeekFrom::Start(self.head))?;
+        self.file.read_to_end(&mut records)?;
+
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&records)?;
+        tmp.sync_data()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.head = 0;
+        self.synced_head = u64::MAX;
+        self.sync()
+    }
+}
+
+impl Drop for DiskQueue {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            log::warn!("Failed to sync queue {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// A record stored in the queue of a sink.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+enum QueuedRecord {
+    Measurement(DhtSensors),
+    Aggregate(AggregateSensors),
+}
+
+/// Buffer data in a disk queue when a sink fails, replaying it in order once the sink recovers.
+pub struct QueuedSink {
+    sink: Box<dyn Sink>,
+    queue: DiskQueue,
+}
+
+impl QueuedSink {
+    pub fn new(sink: Box<dyn Sink>, config: &QueueConfig) -> Result<QueuedSink> {
+        let queue = DiskQueue::open(&config.path, config.max_bytes)?;
+        if !queue.is_empty() {
+            log::info!(
+                "Queue for '{}' sink holds {} records",
+                sink.name(),
+                queue.len()
+            );
+        }
+
+        Ok(QueuedSink { sink, queue })
+    }
+
+    fn send(&mut self, record: &QueuedRecord) -> Result<()> {
+        match record {
+            QueuedRecord::Measurement(measurement) => self.sink.log_measurement(measurement),
+            QueuedRecord::Aggregate(aggregate) => self.sink.log_aggregate(aggregate),
+        }
+    }
+
+    /// Send queued records to the sink until the queue is empty or the sink fails. The head of
+    /// the queue is synced once after replaying, rather than for every record.
+    fn replay(&mut self) -> Result<()> {
+        let mut replayed = 0;
+        let result = self.replay_records(&mut replayed);
+        let synced = self.queue.sync();
+
+        if replayed > 0 {
+            log::info!(
+                "Replayed {} queued records to '{}' sink",
+                replayed,
+                self.sink.name()
+            );
+        }
+
+        result.and(synced)
+    }
+
+    fn replay_records(&mut self, replayed: &mut usize) -> Result<()> {
+        while let Some(record) = self.queue.front()? {
+            match serde_json::from_slice::<QueuedRecord>(&record) {
+                Ok(record) => self.send(&record)?,
+                Err(err) => log::warn!("Dropping corrupt queued record: {}", err),
+            }
+            self.queue.pop()?;
+            *replayed += 1;
+        }
+
+        Ok(())
+    }
+
+    fn log(&mut self, record: QueuedRecord) -> Result<()> {
+        let result = match self.replay() {
+            Ok(()) => self.send(&record),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            self.queue.push(&serde_json::to_vec(&record)?)?;
+            log::warn!(
+                "Failed to log to '{}' sink, queued {} records: {}",
+                self.sink.name(),
+                self.queue.len(),
+                err
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for QueuedSink {
+    fn name(&self) -> &str {
+        self.sink.name()
+    }
+
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+        self.log(QueuedRecord::Measurement(measurement.clone()))
+    }
+
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()> {
+        self.log(QueuedRecord::Aggregate(aggregate.clone()))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Err(err) = self.replay() {
+            log::warn!(
+                "Failed to replay queue of '{}' sink: {}",
+                self.sink.name(),
+                err
+            );
+        }
+        self.sink.flush()
+    }
+
+    fn metrics(&self) -> SinkMetrics {
+        SinkMetrics {
+            queue_depth: Some(self.queue.len()),
+            queue_dropped: self.queue.dropped(),
+            ..self.sink.metrics()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::aggregate::Aggregator;
+    use crate::sinks::UdpSink;
+    use crate::wire::{decode, Payload};
+
+    fn queue_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dht-logger-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // Test that records are persisted in order and the oldest records are dropped when full
+    #[test]
+    fn test_disk_queue() {
+        let path = queue_path("queue");
+        let mut queue = DiskQueue::open(&path, 12).unwrap();
+        queue.push(b"one").unwrap();
+        queue.push(b"two").unwrap();
+        queue.push(b"three").unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.bytes(), 10);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.front().unwrap().unwrap(), b"two");
+        queue.pop().unwrap();
+        // The head is stored when the queue is dropped.
+        drop(queue);
+
+        let mut queue = DiskQueue::open(&path, 12).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.bytes(), 6);
+        assert_eq!(queue.front().unwrap().unwrap(), b"three");
+        queue.pop().unwrap();
+        assert!(queue.front().unwrap().is_none());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        fs::remove_file(queue.path()).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+    }
+
+    struct FlakySink {
+        up: Arc<AtomicBool>,
+        received: Arc<Mutex<Vec<i64>>>,
+    }
+
+    impl Sink for FlakySink {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+            if !self.up.load(Ordering::SeqCst) {
+                return Err(Error::new(ErrorKind::NotConnected, "sink is down"));
+            }
+            self.received
+                .lock()
+                .unwrap()
+                .push(measurement.timestamp.timestamp());
+            Ok(())
+        }
+
+        fn log_aggregate(&mut self, _: &AggregateSensors) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Test that measurements are replayed in order once a sink recovers
+    #[test]
+    fn test_queued_sink() {
+        let path = queue_path("sink");
+        let up = Arc::new(AtomicBool::new(true));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = FlakySink {
+            up: up.clone(),
+            received: received.clone(),
+        };
+        let config = QueueConfig {
+            path: path.clone(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        };
+        let mut sink = QueuedSink::new(Box::new(sink), &config).unwrap();
+
+        let measurement = |secs| DhtSensors {
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            unit: Default::default(),
+            data: Default::default(),
+            errors: Default::default(),
+        };
+
+        sink.log_measurement(&measurement(1)).unwrap();
+        up.store(false, Ordering::SeqCst);
+        sink.log_measurement(&measurement(2)).unwrap();
+        sink.log_measurement(&measurement(3)).unwrap();
+        assert_eq!(sink.metrics().queue_depth, Some(2));
+
+        up.store(true, Ordering::SeqCst);
+        sink.log_measurement(&measurement(4)).unwrap();
+        assert_eq!(sink.metrics().queue_depth, Some(0));
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 3, 4]);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+    }
+
+    // Test that records with NaN extra measurements survive the queue instead of being dropped
+    #[test]
+    fn test_queued_nan() {
+        let path = queue_path("nan");
+        let up = Arc::new(AtomicBool::new(false));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = FlakySink {
+            up: up.clone(),
+            received: received.clone(),
+        };
+        let config = QueueConfig {
+            path: path.clone(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        };
+        let mut sink = QueuedSink::new(Box::new(sink), &config).unwrap();
+
+        let data = SensorData {
+            extra: BTreeMap::from([(String::from("co2"), f32::NAN)]),
+            ..Default::default()
+        };
+        let measurement = DhtSensors {
+            timestamp: Utc.timestamp_opt(1, 0).unwrap(),
+            unit: Default::default(),
+            data: HashMap::from([(String::from("a"), data.clone())]),
+            errors: Default::default(),
+        };
+        sink.log_measurement(&measurement).unwrap();
+        assert_eq!(sink.metrics().queue_depth, Some(1));
+        up.store(true, Ordering::SeqCst);
+        sink.flush().unwrap();
+        assert_eq!(sink.metrics().queue_depth, Some(0));
+        assert_eq!(*received.lock().unwrap(), vec![1]);
+
+        let mut aggregator = Aggregator::new(Duration::from_secs(60)).unwrap();
+        aggregator.push(&measurement);
+        let record = QueuedRecord::Aggregate(aggregator.finish().unwrap());
+        let record = serde_json::to_vec(&record).unwrap();
+        match serde_json::from_slice::<QueuedRecord>(&record).unwrap() {
+            QueuedRecord::Aggregate(aggregate) => {
+                assert!(aggregate.data["a"].extra["co2"].mean.is_nan())
+            }
+            record => panic!("expected an aggregate, got {:?}", record),
+        }
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+    }
+
+    // Test that an unreachable UDP destination does not hold back a reachable one behind a queue
+    #[test]
+    fn test_queued_udp_sink() {
+        let path = queue_path("udp");
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        // Sending to port 0 always fails.
+        let unreachable = SocketAddr::from(([127, 0, 0, 1], 0));
+        let config = QueueConfig {
+            path: path.clone(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        };
+        let sink = UdpSink::new(vec![listener.local_addr().unwrap(), unreachable]).unwrap();
+        let mut sink = QueuedSink::new(Box::new(sink), &config).unwrap();
+
+        let mut buffer = [0; 1024];
+        for secs in 1..=3 {
+            let measurement = DhtSensors {
+                timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+                unit: Default::default(),
+                data: Default::default(),
+                errors: Default::default(),
+            };
+            sink.log_measurement(&measurement).unwrap();
+            assert_eq!(sink.metrics().queue_depth, Some(0));
+
+            let n_bytes = listener.recv(&mut buffer).unwrap();
+            let packet = decode(&buffer[..n_bytes]).unwrap();
+            match packet.payload {
+                Payload::Measurement(data) => assert_eq!(data.timestamp.timestamp(), secs),
+                payload => panic!("expected a measurement, got {:?}", payload),
+            }
+        }
+
+        // Records are only queued when no destination can be sent to.
+        let sink = UdpSink::new(vec![unreachable]).unwrap();
+        let mut sink = QueuedSink::new(Box::new(sink), &config).unwrap();
+        sink.log_measurement(&DhtSensors {
+            timestamp: Utc.timestamp_opt(4, 0).unwrap(),
+            unit: Default::default(),
+            data: Default::default(),
+            errors: Default::default(),
+        })
+        .unwrap();
+        assert_eq!(sink.metrics().queue_depth, Some(1));
+        drop(sink);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+    }
+}
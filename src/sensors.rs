@@ -0,0 +1,232 @@
+//! Registry of metadata describing the DHT sensors attached to a device.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::messages::*;
+use super::Result;
+
+/// Model of a DHT sensor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum SensorModel {
+    #[serde(rename = "DHT11", alias = "dht11")]
+    Dht11,
+    #[serde(rename = "DHT22", alias = "dht22")]
+    Dht22,
+    #[serde(rename = "AM2302", alias = "am2302")]
+    Am2302,
+}
+
+impl fmt::Display for SensorModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SensorModel::Dht11 => "DHT11",
+            SensorModel::Dht22 => "DHT22",
+            SensorModel::Am2302 => "AM2302",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Metadata attached to a sensor label.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SensorMetadata {
+    /// Human-readable name of the sensor.
+    pub name: Option<String>,
+    /// Location or room of the sensor.
+    pub location: Option<String>,
+    /// Model of the sensor.
+    pub model: Option<SensorModel>,
+    /// Arbitrary key-value tags.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// How to handle readings of sensors that are not in the registry.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownSensors {
+    /// Accept readings of unknown sensors silently.
+    #[default]
+    Accept,
+    /// Accept readings of unknown sensors, but log a warning the first time each one is seen.
+    Log,
+    /// Drop readings of unknown sensors.
+    Reject,
+}
+
+/// Metadata of all sensors, keyed by the sensor label sent by the device.
+///
+/// Example configuration YAML (inside of `logger_config`):
+/// ```yaml
+/// sensors:
+///   sensor_label:
+///     name: Living room
+///     location: downstairs
+///     model: DHT22
+///     tags:
+///       floor: "1"
+/// # One of: accept, log, reject
+/// unknown_sensors: log
+/// ```
+#[derive(Debug, Default)]
+pub struct SensorRegistry {
+    sensors: HashMap<String, SensorMetadata>,
+    unknown: UnknownSensors,
+    warned: Mutex<HashSet<String>>,
+}
+
+impl SensorRegistry {
+    /// Create a sensor registry.
+    pub fn new(sensors: HashMap<String, SensorMetadata>, unknown: UnknownSensors) -> Self {
+        SensorRegistry {
+            sensors,
+            unknown,
+            warned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Create a sensor registry from the `sensors` and `unknown_sensors` logger config options.
+    /// Returns an `InvalidData` error if either cannot be parsed.
+    pub fn from_logger_config(logger_config: &HashMap<String, Value>) -> Result<Self> {
+        let sensors = match logger_config.get("sensors") {
+            Some(sensors) => serde_json::from_value(sensors.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.sensors: {}", err),
+                )
+            })?,
+            None => HashMap::new(),
+        };
+
+        let unknown = match logger_config.get("unknown_sensors") {
+            Some(unknown) => serde_json::from_value(unknown.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.unknown_sensors: {}", err),
+                )
+            })?,
+            None => UnknownSensors::default(),
+        };
+
+        Ok(SensorRegistry::new(sensors, unknown))
+    }
+
+    /// Get the metadata of a sensor.
+    pub fn get(&self, label: &str) -> Option<&SensorMetadata> {
+        self.sensors.get(label)
+    }
+
+    /// Get all tag keys used by any sensor, in sorted order.
+    pub fn tag_keys(&self) -> Vec<String> {
+        let keys: BTreeSet<&String> = self
+            .sensors
+            .values()
+            .flat_map(|metadata| metadata.tags.keys())
+            .collect();
+        keys.into_iter().cloned().collect()
+    }
+
+    /// Apply the unknown sensor policy to a measurement, removing any rejected sensors.
+    pub fn check(&self, mut measurement: DhtSensors) -> DhtSensors {
+        if self.unknown == UnknownSensors::Accept {
+            return measurement;
+        }
+
+        measurement.data.retain(|label, _| {
+            if self.sensors.contains_key(label) {
+                return true;
+            }
+
+            match self.unknown {
+                UnknownSensors::Reject => {
+                    log::trace!("Rejected reading of unknown sensor: {}", label);
+                    false
+                }
+                _ => {
+                    if self.warned.lock().unwrap().insert(label.clone()) {
+                        log::warn!(
+                            sensor = label.as_str(), error_kind = "unknown";
+                            "Received reading of unknown sensor: {}", label
+                        );
+                    }
+                    true
+                }
+            }
+        });
+
+        measurement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn registry(unknown: UnknownSensors) -> SensorRegistry {
+        let sensors = serde_yaml::from_str(
+            r#"
+            kitchen:
+              name: Kitchen
+              model: DHT22
+              tags: {floor: "1"}
+            attic:
+              model: dht11
+              tags: {floor: "3", vent: "yes"}
+            "#,
+        )
+        .unwrap();
+        SensorRegistry::new(sensors, unknown)
+    }
+
+    fn measurement() -> DhtSensors {
+        let data = ["kitchen", "garage"]
+            .iter()
+            .map(|label| {
+                let data = SensorData::default();
+                (String::from(*label), data)
+            })
+            .collect();
+
+        DhtSensors {
+            timestamp: Utc::now(),
+            unit: Default::default(),
+            data,
+            errors: HashMap::new(),
+        }
+    }
+
+    // Test that metadata is parsed and tag keys are collected
+    #[test]
+    fn test_metadata() {
+        let registry = registry(UnknownSensors::Accept);
+        let kitchen = registry.get("kitchen").unwrap();
+        assert_eq!(kitchen.name.as_deref(), Some("Kitchen"));
+        assert_eq!(kitchen.model, Some(SensorModel::Dht22));
+        assert_eq!(
+            registry.get("attic").unwrap().model,
+            Some(SensorModel::Dht11)
+        );
+        assert!(registry.get("garage").is_none());
+        assert_eq!(registry.tag_keys(), vec!["floor", "vent"]);
+    }
+
+    // Test the unknown sensor policies
+    #[test]
+    fn test_unknown_sensors() {
+        let checked = registry(UnknownSensors::Accept).check(measurement());
+        assert_eq!(checked.data.len(), 2);
+        let checked = registry(UnknownSensors::Log).check(measurement());
+        assert_eq!(checked.data.len(), 2);
+        let checked = registry(UnknownSensors::Reject).check(measurement());
+        assert_eq!(checked.data.len(), 1);
+        assert!(checked.data.contains_key("kitchen"));
+    }
+}
@@ -0,0 +1,208 @@
+//! Serial line settings of a DHT logger device.
+
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serialport::{DataBits, FlowControl, Parity, SerialPort, SerialPortBuilder, StopBits};
+
+use super::{Result, TIMEOUT};
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+fn default_stop_bits() -> u8 {
+    1
+}
+
+fn default_timeout_ms() -> u64 {
+    TIMEOUT.as_millis() as u64
+}
+
+/// Parity checking of the serial line.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialParity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+/// Flow control of the serial line.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialFlowControl {
+    #[default]
+    None,
+    /// XON/XOFF flow control.
+    Software,
+    /// RTS/CTS flow control.
+    Hardware,
+}
+
+/// Serial line settings of the device. The defaults are 8N1 without flow control.
+///
+/// `dtr` and `rts` set the state of the control lines right after opening the port, and are left
+/// as set by the driver if omitted. Setting `dtr: false` keeps most Arduino boards from resetting
+/// on every open, though on Linux the driver briefly asserts both lines while opening the port.
+///
+/// Example configuration YAML (at the top level of the config):
+/// ```yaml
+/// serial:
+///   data_bits: 8
+///   parity: none
+///   stop_bits: 1
+///   flow_control: hardware
+///   timeout_ms: 4000
+///   dtr: false
+///   rts: false
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SerialConfig {
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default)]
+    pub parity: SerialParity,
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+    #[serde(default)]
+    pub flow_control: SerialFlowControl,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dtr: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rts: Option<bool>,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            data_bits: default_data_bits(),
+            parity: SerialParity::default(),
+            stop_bits: default_stop_bits(),
+            flow_control: SerialFlowControl::default(),
+            timeout_ms: default_timeout_ms(),
+            dtr: None,
+            rts: None,
+        }
+    }
+}
+
+impl SerialConfig {
+    /// Check that the settings are supported, returning an `InvalidData` error otherwise.
+    pub fn validate(&self) -> Result<()> {
+        self.data_bits()?;
+        self.stop_bits()?;
+        if self.timeout_ms == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "serial.timeout_ms must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+
+    fn data_bits(&self) -> Result<DataBits> {
+        match self.data_bits {
+            5 => Ok(DataBits::Five),
+            6 => Ok(DataBits::Six),
+            7 => Ok(DataBits::Seven),
+            8 => Ok(DataBits::Eight),
+            bits => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("serial.data_bits must be between 5 and 8, got {}", bits),
+            )),
+        }
+    }
+
+    fn stop_bits(&self) -> Result<StopBits> {
+        match self.stop_bits {
+            1 => Ok(StopBits::One),
+            2 => Ok(StopBits::Two),
+            bits => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("serial.stop_bits must be 1 or 2, got {}", bits),
+            )),
+        }
+    }
+
+    /// Get the read timeout of the port.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Create a builder for a serial port with these settings.
+    pub fn builder(&self, path: &str, baud: u32) -> Result<SerialPortBuilder> {
+        let parity = match self.parity {
+            SerialParity::None => Parity::None,
+            SerialParity::Odd => Parity::Odd,
+            SerialParity::Even => Parity::Even,
+        };
+        let flow_control = match self.flow_control {
+            SerialFlowControl::None => FlowControl::None,
+            SerialFlowControl::Software => FlowControl::Software,
+            SerialFlowControl::Hardware => FlowControl::Hardware,
+        };
+
+        Ok(serialport::new(path, baud)
+            .data_bits(self.data_bits()?)
+            .parity(parity)
+            .stop_bits(self.stop_bits()?)
+            .flow_control(flow_control)
+            .timeout(self.timeout()))
+    }
+
+    /// Set the control lines of an opened port.
+    pub fn set_control_lines<P: SerialPort + ?Sized>(&self, port: &mut P) -> Result<()> {
+        if let Some(dtr) = self.dtr {
+            port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = self.rts {
+            port.write_request_to_send(rts)?;
+        }
+        Ok(())
+    }
+
+    /// Open a serial port with these settings.
+    pub fn open(&self, path: &str, baud: u32) -> Result<Box<dyn SerialPort>> {
+        let mut port = self.builder(path, baud)?.open()?;
+        self.set_control_lines(port.as_mut())?;
+        Ok(port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that omitted settings default to 8N1 and that unsupported framing is rejected
+    #[test]
+    fn test_serial_config() {
+        let config: SerialConfig = serde_yaml::from_str("parity: even\ndtr: false").unwrap();
+        assert_eq!(config.data_bits, 8);
+        assert_eq!(config.stop_bits, 1);
+        assert_eq!(config.parity, SerialParity::Even);
+        assert_eq!(config.timeout(), TIMEOUT);
+        assert_eq!(config.dtr, Some(false));
+        assert!(config.validate().is_ok());
+
+        let builder = config.builder("/dev/ttyUSB0", 9600).unwrap();
+        assert_eq!(
+            builder,
+            serialport::new("/dev/ttyUSB0", 9600)
+                .parity(Parity::Even)
+                .timeout(TIMEOUT)
+        );
+
+        for invalid in ["data_bits: 9", "stop_bits: 3", "timeout_ms: 0"] {
+            let config: SerialConfig = serde_yaml::from_str(invalid).unwrap();
+            assert_eq!(
+                config.validate().unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+    }
+}
@@ -0,0 +1,640 @@
+//! Logging channels receiving DHT sensor measurements.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::dispatch::{DispatchConfig, ThreadedSink};
+use super::messages::*;
+use super::queue::{QueueConfig, QueuedSink};
+use super::sensors::SensorRegistry;
+use super::udp::{self, Destinations, UdpOptions};
+use super::units::TemperatureUnit;
+use super::wire::{Encoder, WireConfig, CONTENT_JSON};
+use super::Result;
+
+/// Options common to all sinks, configured per sink name in `sinks`.
+///
+/// Example configuration YAML (inside of `logger_config`):
+/// ```yaml
+/// sinks:
+///   udp:
+///     units: fahrenheit
+///     queue:
+///       path: /var/lib/dht-logger/udp.queue
+///     dispatch:
+///       capacity: 64
+///       overflow: drop_oldest
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SinkOptions {
+    /// Temperature unit of the data sent to the sink. Data is sent in Celsius if not set.
+    pub units: Option<TemperatureUnit>,
+    /// Disk queue buffering data while the sink is failing.
+    pub queue: Option<QueueConfig>,
+    /// Buffer of the worker thread running the sink.
+    pub dispatch: DispatchConfig,
+}
+
+/// Metrics reported by a sink.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SinkMetrics {
+    /// Number of records processed by the sink worker.
+    pub sent: u64,
+    /// Number of records the sink failed to log.
+    pub errors: u64,
+    /// Number of records dropped because the buffer of the sink worker was full.
+    pub dropped: u64,
+    /// Time between logging the most recent record and the sink finishing with it.
+    pub last_latency: Option<Duration>,
+    /// Maximum time between logging a record and the sink finishing with it.
+    pub max_latency: Option<Duration>,
+    /// Number of records waiting in the queue of the sink, if it has a queue.
+    pub queue_depth: Option<usize>,
+    /// Number of records dropped from the queue because it was full.
+    pub queue_dropped: u64,
+}
+
+/// A logging channel for DHT sensor measurements.
+///
+/// Every sink receives either raw measurements or aggregates, depending on the aggregation
+/// configuration of the DHT logger.
+pub trait Sink: Send {
+    /// Name of the sink as used in the logger config.
+    fn name(&self) -> &str;
+
+    /// Log a single measurement of all DHT sensors.
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()>;
+
+    /// Log an aggregate of measurements over a time window.
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()>;
+
+    /// Flush any data buffered by the sink.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get the metrics of the sink.
+    fn metrics(&self) -> SinkMetrics {
+        SinkMetrics::default()
+    }
+}
+
+/// Log incoming data using `log::info!` if verbose, or `log::debug!` otherwise.
+pub struct LogSink {
+    verbose: bool,
+}
+
+impl LogSink {
+    pub fn new(verbose: bool) -> LogSink {
+        LogSink { verbose }
+    }
+
+    fn log(&self, message: String) {
+        if self.verbose {
+            log::info!("{}", message);
+        } else {
+            log::debug!("{}", message);
+        }
+    }
+}
+
+impl Sink for LogSink {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+        let data_pretty = serde_json::to_string_pretty(measurement)?;
+        self.log(format!("Received measurement:\n{}", data_pretty));
+        Ok(())
+    }
+
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()> {
+        let data_pretty = serde_json::to_string_pretty(aggregate)?;
+        self.log(format!("Aggregated measurements:\n{}", data_pretty));
+        Ok(())
+    }
+}
+
+/// Send data as compact JSON, or in a binary encoding, to a list of UDP addresses.
+///
+/// Measurements are serialized as `DhtSensorsSerde` and aggregates as `AggregateSensors`, inside
+/// of the envelope of the wire format (see the `wire` module).
+pub struct UdpSink {
+    destinations: Destinations,
+    socket: UdpSocket,
+    encoder: Encoder,
+}
+
+impl UdpSink {
+    pub fn new<A: Into<SocketAddr>>(addrs: Vec<A>) -> Result<UdpSink> {
+        let addrs: Vec<SocketAddr> = addrs.into_iter().map(Into::into).collect();
+        UdpSink::with_options(Destinations::from(addrs), &UdpOptions::default())
+    }
+
+    /// Create a UDP sink sending to destinations that may be given by hostname, from a socket
+    /// with the given options, such as a multicast TTL.
+    pub fn with_options(destinations: Destinations, options: &UdpOptions) -> Result<UdpSink> {
+        let socket = options.socket()?;
+        Ok(UdpSink {
+            destinations,
+            socket,
+            encoder: Encoder::default(),
+        })
+    }
+
+    /// Use an encoder for a configured wire format instead of the default one.
+    pub fn with_encoder(mut self, encoder: Encoder) -> UdpSink {
+        self.encoder = encoder;
+        self
+    }
+
+    /// Send data to every destination. A destination that cannot be reached is logged and does
+    /// not keep the data from the others. An error is only returned if no destination could be
+    /// sent to, so that a queue in front of the sink does not hold back the healthy destinations.
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        if data.first() == Some(&CONTENT_JSON) {
+            log::trace!("{}", String::from_utf8_lossy(data));
+        }
+        let ipv6 = self.socket.local_addr()?.is_ipv6();
+        let mut sent_any = false;
+        let mut last_error = None;
+        for addr in self.destinations.addrs(ipv6) {
+            let sent = udp::target(&self.socket, addr)
+                .and_then(|target| self.socket.send_to(data, target));
+            match sent {
+                Ok(bytes_sent) => {
+                    log::trace!("Sent {} bytes to UDP addr: {:?}", bytes_sent, addr);
+                    sent_any = true;
+                }
+                Err(err) => {
+                    log::warn!("Failed to send to UDP addr {}: {}", addr, err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match last_error {
+            Some(err) if !sent_any => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Sink for UdpSink {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+        let data = self.encoder.encode_measurement(measurement)?;
+        self.send(&data)
+    }
+
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()> {
+        let data = self.encoder.encode_aggregate(aggregate)?;
+        self.send(&data)
+    }
+}
+
+/// Kind of records written to a CSV file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CsvRecords {
+    Measurements,
+    Aggregates,
+}
+
+/// Append data as rows to a CSV file, with one row per sensor.
+///
+/// Each row contains the sensor metadata from the sensor registry, with one column per tag key.
+/// Measurements are written with the columns
+/// `timestamp,label,name,location,model,<tags>,unit,temperature,humidity,heat_index,extra`, where
+/// `extra` holds any extra measurements of the sensor as `name=value` pairs separated by `;`, and
+/// aggregates with one row per field, including extra measurements, with the columns
+/// `start,end,label,name,location,model,<tags>,unit,field,count,min,max,mean,stddev`.
+/// A header is written when the file is empty. If an existing file has a different header, for
+/// example after the tag keys changed, it is moved aside to `<path>.<timestamp>` and a new file is
+/// started, so that the columns of every file match its header.
+///
+/// The CSV sink is the only sink writing sensor metadata. The UDP sink only sends sensor labels.
+pub struct CsvSink {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    registry: Arc<SensorRegistry>,
+    tag_keys: Vec<String>,
+    records: Option<CsvRecords>,
+}
+
+impl CsvSink {
+    pub fn new(path: &Path, registry: Arc<SensorRegistry>) -> Result<CsvSink> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let tag_keys = registry.tag_keys();
+
+        Ok(CsvSink {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            registry,
+            tag_keys,
+            records: None,
+        })
+    }
+
+    /// Get the path of the CSV file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn escape(value: &str) -> String {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            String::from(value)
+        }
+    }
+
+    fn metadata_columns(&self, label: &str) -> Vec<String> {
+        let metadata = self.registry.get(label);
+        let mut columns = vec![
+            String::from(label),
+            metadata
+                .and_then(|metadata| metadata.name.clone())
+                .unwrap_or_default(),
+            metadata
+                .and_then(|metadata| metadata.location.clone())
+                .unwrap_or_default(),
+            metadata
+                .and_then(|metadata| metadata.model)
+                .map(|model| model.to_string())
+                .unwrap_or_default(),
+        ];
+        for key in self.tag_keys.iter() {
+            columns.push(
+                metadata
+                    .and_then(|metadata| metadata.tags.get(key).cloned())
+                    .unwrap_or_default(),
+            );
+        }
+
+        columns
+    }
+
+    fn write_header(&mut self, records: CsvRecords, first: &[&str], last: &[&str]) -> Result<()> {
+        match self.records {
+            Some(current) if current != records => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "CSV sink cannot mix measurements and aggregates",
+                ));
+            }
+            Some(_) => return Ok(()),
+            None => self.records = Some(records),
+        }
+
+        let mut header: Vec<String> = first.iter().map(|column| column.to_string()).collect();
+        header.extend(["label", "name", "location", "model"].map(String::from));
+        header.extend(self.tag_keys.iter().cloned());
+        header.extend(last.iter().map(|column| column.to_string()));
+        let header = CsvSink::format_row(&header);
+
+        if self.writer.get_ref().metadata()?.len() > 0 {
+            let mut existing = String::new();
+            BufReader::new(File::open(&self.path)?).read_line(&mut existing)?;
+            if existing.trim_end_matches(['\r', '\n']) == header {
+                return Ok(());
+            }
+            self.rotate()?;
+        }
+
+        writeln!(self.writer, "{}", header)
+    }
+
+    /// Move the CSV file aside to a path with the current time appended, and start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%S");
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), timestamp));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}.{}", self.path.display(), timestamp, n));
+            n += 1;
+        }
+
+        fs::rename(&self.path, &rotated)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        log::warn!(
+            "Header of CSV file {} does not match the current columns, moved it to {}",
+            self.path.display(),
+            rotated.display()
+        );
+        Ok(())
+    }
+
+    fn format_row(row: &[String]) -> String {
+        let row: Vec<String> = row.iter().map(|column| CsvSink::escape(column)).collect();
+        row.join(",")
+    }
+
+    fn write_row(&mut self, row: Vec<String>) -> Result<()> {
+        writeln!(self.writer, "{}", CsvSink::format_row(&row))
+    }
+}
+
+impl Sink for CsvSink {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+        self.write_header(
+            CsvRecords::Measurements,
+            &["timestamp"],
+            &["unit", "temperature", "humidity", "heat_index", "extra"],
+        )?;
+
+        let mut labels: Vec<&String> = measurement.data.keys().collect();
+        labels.sort();
+        for label in labels {
+            let data = &measurement.data[label];
+            let mut row = vec![measurement.timestamp.to_rfc3339()];
+            row.extend(self.metadata_columns(label));
+            row.push(measurement.unit.to_string());
+            row.extend([data.temperature, data.humidity, data.heat_index].map(|v| v.to_string()));
+            let extra: Vec<String> = data
+                .extra
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            row.push(extra.join(";"));
+            self.write_row(row)?;
+        }
+
+        self.writer.flush()
+    }
+
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()> {
+        self.write_header(
+            CsvRecords::Aggregates,
+            &["start", "end"],
+            &["unit", "field", "count", "min", "max", "mean", "stddev"],
+        )?;
+
+        let mut labels: Vec<&String> = aggregate.data.keys().collect();
+        labels.sort();
+        for label in labels {
+            let data = &aggregate.data[label];
+            let mut fields = vec![
+                ("temperature", data.temperature),
+                ("humidity", data.humidity),
+                ("heat_index", data.heat_index),
+            ];
+            fields.extend(
+                data.extra
+                    .iter()
+                    .map(|(name, stats)| (name.as_str(), *stats)),
+            );
+            for (field, stats) in fields {
+                let mut row = vec![aggregate.start.to_rfc3339(), aggregate.end.to_rfc3339()];
+                row.extend(self.metadata_columns(label));
+                row.push(aggregate.unit.to_string());
+                row.push(String::from(field));
+                row.push(stats.count.to_string());
+                row.extend([stats.min, stats.max, stats.mean, stats.stddev].map(|v| v.to_string()));
+                self.write_row(row)?;
+            }
+        }
+
+        self.writer.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Convert all data to a temperature unit before passing it to another sink.
+pub struct UnitSink {
+    sink: Box<dyn Sink>,
+    unit: TemperatureUnit,
+}
+
+impl UnitSink {
+    pub fn new(sink: Box<dyn Sink>, unit: TemperatureUnit) -> UnitSink {
+        UnitSink { sink, unit }
+    }
+}
+
+impl Sink for UnitSink {
+    fn name(&self) -> &str {
+        self.sink.name()
+    }
+
+    fn log_measurement(&mut self, measurement: &DhtSensors) -> Result<()> {
+        self.sink.log_measurement(&measurement.to_unit(self.unit))
+    }
+
+    fn log_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<()> {
+        self.sink.log_aggregate(&aggregate.to_unit(self.unit))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sink.flush()
+    }
+
+    fn metrics(&self) -> SinkMetrics {
+        self.sink.metrics()
+    }
+}
+
+/// Parse the per-sink options in the logger config.
+fn sink_options(logger_config: &HashMap<String, Value>) -> Result<HashMap<String, SinkOptions>> {
+    match logger_config.get("sinks") {
+        Some(options) => serde_json::from_value(options.clone()).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse logger.sinks: {}", err),
+            )
+        }),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Add the name of a sink to an error opening it, keeping the kind of the error.
+fn context(err: Error, reason: &str) -> Error {
+    Error::new(err.kind(), format!("{}: {}", reason, err))
+}
+
+/// Wrap a sink according to its options. Every sink is run on a worker thread.
+fn with_options(sink: Box<dyn Sink>, options: &SinkOptions) -> Result<Box<dyn Sink>> {
+    let sink: Box<dyn Sink> = match options.units {
+        Some(unit) => Box::new(UnitSink::new(sink, unit)),
+        None => sink,
+    };
+
+    let name = sink.name().to_owned();
+    let sink: Box<dyn Sink> =
+        match &options.queue {
+            Some(queue) => Box::new(QueuedSink::new(sink, queue).map_err(|err| {
+                context(err, &format!("Failed to open queue for '{}' sink", name))
+            })?),
+            None => sink,
+        };
+
+    let sink = ThreadedSink::new(sink, options.dispatch.clone())
+        .map_err(|err| context(err, &format!("Failed to start worker of '{}' sink", name)))?;
+    Ok(Box::new(sink))
+}
+
+/// Sinks configured in a logger config, parsed and validated without opening any of them.
+///
+/// Supported sinks:
+/// * `log`: Always enabled. `verbose` selects between `log::info!` and `log::debug!`.
+/// * `udp`: Enabled by a list of `IP:PORT` or `HOST:PORT` addresses in `udp`, with the wire
+///   format configured by `wire` (see `WireConfig`) and the socket by `udp_options` (see
+///   `UdpOptions`).
+/// * `csv`: Enabled by a file path in `csv`.
+///
+/// Each sink is configured further by its entry in `sinks` (see `SinkOptions`).
+pub struct SinksConfig {
+    verbose: bool,
+    udp: Option<(Destinations, UdpOptions, Encoder)>,
+    csv: Option<PathBuf>,
+    options: HashMap<String, SinkOptions>,
+}
+
+impl SinksConfig {
+    /// Parse the sinks of the logger config, returning an `InvalidData` error if the config is
+    /// invalid.
+    pub fn from_logger_config(logger_config: &HashMap<String, Value>) -> Result<SinksConfig> {
+        let invalid = |reason: String| Error::new(ErrorKind::InvalidData, reason);
+        let verbose = match logger_config.get("verbose") {
+            Some(Value::Bool(verbose)) => *verbose,
+            Some(verbose) => {
+                return Err(invalid(format!(
+                    "logger.verbose must be boolean, got value: {}",
+                    verbose
+                )))
+            }
+            None => false,
+        };
+
+        let default = Value::Array(Vec::new());
+        let udp_addrs: Vec<&str> = logger_config
+            .get("udp")
+            .unwrap_or(&default)
+            .as_array()
+            .ok_or_else(|| invalid(String::from("logger.udp must be a list")))?
+            .iter()
+            .map(|addr| {
+                addr.as_str().ok_or_else(|| {
+                    invalid(format!(
+                        "UDP addresses must be strings, got value: {}",
+                        addr
+                    ))
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        // The wire format and the socket options are validated even without UDP addresses.
+        let wire = WireConfig::from_logger_config(logger_config)?;
+        let udp_options = UdpOptions::from_logger_config(logger_config)?;
+        let udp = if udp_addrs.is_empty() {
+            None
+        } else {
+            let destinations = Destinations::parse(&udp_addrs, udp_options.resolve_interval())?;
+            Some((destinations, udp_options, Encoder::from_config(&wire)?))
+        };
+
+        let csv = match logger_config.get("csv") {
+            Some(Value::String(path)) => Some(PathBuf::from(path)),
+            Some(path) => {
+                return Err(invalid(format!(
+                    "logger.csv must be a path, got value: {}",
+                    path
+                )))
+            }
+            None => None,
+        };
+
+        let config = SinksConfig {
+            verbose,
+            udp,
+            csv,
+            options: sink_options(logger_config)?,
+        };
+        let names = config.names();
+        for (name, options) in config.options.iter() {
+            if !names.contains(&name.as_str()) {
+                return Err(invalid(format!(
+                    "logger.sinks refers to unconfigured sink: {}",
+                    name
+                )));
+            }
+            options
+                .dispatch
+                .validate()
+                .map_err(|err| context(err, &format!("Invalid options of '{}' sink", name)))?;
+        }
+        Ok(config)
+    }
+
+    /// Get the names of the configured sinks.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = vec!["log"];
+        if self.udp.is_some() {
+            names.push("udp");
+        }
+        if self.csv.is_some() {
+            names.push("csv");
+        }
+        names
+    }
+
+    /// Open all sinks, returning the error of the first sink that cannot be opened.
+    pub fn open(self, registry: &Arc<SensorRegistry>) -> Result<Vec<Box<dyn Sink>>> {
+        let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(LogSink::new(self.verbose))];
+        if let Some((destinations, options, encoder)) = self.udp {
+            let sink = UdpSink::with_options(destinations, &options)
+                .map_err(|err| context(err, "Failed to open UDP socket"))?;
+            sinks.push(Box::new(sink.with_encoder(encoder)));
+        }
+
+        if let Some(path) = self.csv {
+            let sink = CsvSink::new(&path, registry.clone()).map_err(|err| {
+                context(err, &format!("Failed to open CSV file {}", path.display()))
+            })?;
+            sinks.push(Box::new(sink));
+        }
+
+        let default = SinkOptions::default();
+        sinks
+            .into_iter()
+            .map(|sink| {
+                let options = self.options.get(sink.name()).unwrap_or(&default);
+                with_options(sink, options)
+            })
+            .collect()
+    }
+}
+
+/// Create all sinks configured in the logger config (see `SinksConfig`). Returns an
+/// `InvalidData` error if the config is invalid, or the error of opening a sink.
+pub fn from_logger_config(
+    logger_config: &HashMap<String, Value>,
+    registry: &Arc<SensorRegistry>,
+) -> Result<Vec<Box<dyn Sink>>> {
+    SinksConfig::from_logger_config(logger_config)?.open(registry)
+}
@@ -0,0 +1,716 @@
+//! Sources of the JSON stream of a DHT logger device: local serial ports, raw TCP sockets,
+//! RFC 2217 serial ports over telnet, files, FIFOs or stdin, and sensors read through GPIO.
+
+use std::fs::{self, File};
+use std::io::{self, prelude::*, Error, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::de::IgnoredAny;
+use serialport::SerialPort;
+
+use super::serial::{SerialConfig, SerialFlowControl, SerialParity};
+use super::{Result, StopHandle, BUFFER_SIZE};
+
+/// Minimum time between attempts to reconnect a source.
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A stream of JSON frames from a DHT logger device.
+pub trait Source: Send {
+    /// Read data into `buffer`, blocking until data is available or the read times out. Network
+    /// sources return one complete frame per read.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+    /// Get the name of the source, such as the path of a serial port.
+    fn name(&self) -> Option<String>;
+
+    /// Check if the end of the data was reached. Only sources with a finite input end.
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    /// Use the stop handle of the logger, so that reads waiting for a long time, such as between
+    /// readings of GPIO sensors, return an `Interrupted` error once the logger is stopped.
+    fn set_stop_handle(&mut self, _stop: StopHandle) {}
+}
+
+impl Source for Box<dyn SerialPort> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        Read::read(self, buffer)
+    }
+
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self.as_ref())
+    }
+}
+
+/// Address of a source, parsed from the `port` of the config.
+///
+/// * `tcp://host:port`: A raw TCP socket, such as a ser2net port in raw mode or an ESP-Link bridge.
+/// * `rfc2217://host:port`: A serial port over telnet with the RFC 2217 com port option, such as
+///   a ser2net port in telnet mode. The serial settings of the config are sent to the server.
+/// * `gpio:///dev/gpiochipN`: Sensors read directly through the lines of a GPIO chip, listed in
+///   the `gpio` section of the config (see `GpioConfig`).
+/// * `-`, or the path of a regular file or FIFO: Input read until its end (see `InputSource`).
+/// * Anything else is the path of a local serial port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SourceAddr {
+    Serial(PathBuf),
+    Tcp(String),
+    Rfc2217(String),
+    Gpio(PathBuf),
+    Input(PathBuf),
+}
+
+impl SourceAddr {
+    /// Parse the address of a source.
+    pub fn parse(port: &Path) -> SourceAddr {
+        let name = port.to_string_lossy();
+        if let Some(addr) = name.strip_prefix("tcp://") {
+            SourceAddr::Tcp(addr.to_owned())
+        } else if let Some(addr) = name.strip_prefix("rfc2217://") {
+            SourceAddr::Rfc2217(addr.to_owned())
+        } else if let Some(chip) = name.strip_prefix("gpio://") {
+            SourceAddr::Gpio(PathBuf::from(chip))
+        } else if name == "-" || is_input(port) {
+            SourceAddr::Input(port.to_path_buf())
+        } else {
+            SourceAddr::Serial(port.to_path_buf())
+        }
+    }
+
+    /// Check if the source is reached over the network.
+    pub fn is_network(&self) -> bool {
+        matches!(self, SourceAddr::Tcp(_) | SourceAddr::Rfc2217(_))
+    }
+
+    /// Open the source with the serial settings of the config. For network sources, the timeout
+    /// applies to connecting as well as to reading. GPIO sources are opened with
+    /// `DhtLoggerConfig::open_source`, since they need the `gpio` section of the config.
+    pub fn open(&self, baud: u32, serial: &SerialConfig) -> Result<Box<dyn Source>> {
+        match self {
+            SourceAddr::Serial(path) => {
+                let port = serial.open(&path.to_string_lossy(), baud)?;
+                Ok(Box::new(port))
+            }
+            SourceAddr::Tcp(addr) => {
+                let stream = connect(addr, serial.timeout())?;
+                Ok(Box::new(NetworkSource::new(
+                    stream,
+                    format!("tcp://{}", addr),
+                    None,
+                )))
+            }
+            SourceAddr::Rfc2217(addr) => {
+                let mut stream = connect(addr, serial.timeout())?;
+                stream.write_all(&rfc2217::negotiate(baud, serial))?;
+                Ok(Box::new(NetworkSource::new(
+                    stream,
+                    format!("rfc2217://{}", addr),
+                    Some(rfc2217::Decoder::default()),
+                )))
+            }
+            SourceAddr::Gpio(_) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "GPIO sources need the gpio section of the config",
+            )),
+            SourceAddr::Input(path) if path == Path::new("-") => Ok(Box::new(InputSource::new(
+                String::from("stdin"),
+                io::stdin(),
+                serial.timeout(),
+            ))),
+            SourceAddr::Input(path) => Ok(Box::new(InputSource::new(
+                path.to_string_lossy().into_owned(),
+                File::open(path)?,
+                serial.timeout(),
+            ))),
+        }
+    }
+}
+
+/// Check if a path is a regular file or a FIFO, which are read as input rather than as a serial
+/// port.
+fn is_input(path: &Path) -> bool {
+    let file_type = match fs::metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(_) => return false,
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return true;
+        }
+    }
+    file_type.is_file()
+}
+
+/// Connect to the first reachable address `addr` resolves to.
+fn connect(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut result = Err(Error::new(
+        ErrorKind::NotFound,
+        format!("{} did not resolve to any address", addr),
+    ));
+    for addr in addr.to_socket_addrs()? {
+        result = TcpStream::connect_timeout(&addr, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+
+    let stream = result?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Split a byte stream into complete JSON values. Network streams are not split into frames the
+/// way serial reads are, so a read may return part of a frame or several frames.
+#[derive(Default)]
+pub struct Frames {
+    pending: Vec<u8>,
+}
+
+impl Frames {
+    /// Add data read from the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame, if any. Invalid data, such as a banner of the server or a
+    /// line cut off at connect, is dropped up to the next line or `{` with an `InvalidData` error,
+    /// keeping any frames after it.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let start = match self.pending.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) => start,
+            None => {
+                self.pending.clear();
+                return Ok(None);
+            }
+        };
+        self.pending.drain(..start);
+
+        let mut values =
+            serde_json::Deserializer::from_slice(&self.pending).into_iter::<IgnoredAny>();
+        match values.next() {
+            Some(Ok(_)) => {
+                let end = values.byte_offset();
+                Ok(Some(self.pending.drain(..end).collect()))
+            }
+            Some(Err(err)) if err.is_eof() && self.pending.len() <= BUFFER_SIZE => Ok(None),
+            Some(Err(err)) => {
+                self.skip_invalid();
+                Err(Error::new(ErrorKind::InvalidData, err))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Drop the pending data up to the next line or `{` after the start of the invalid data.
+    fn skip_invalid(&mut self) {
+        let next = self
+            .pending
+            .iter()
+            .skip(1)
+            .position(|&b| b == b'\n' || b == b'{')
+            .map_or(self.pending.len(), |i| i + 1);
+        self.pending.drain(..next);
+    }
+}
+
+/// Copy a frame into the buffer of a read.
+fn copy_frame(frame: &[u8], buffer: &mut [u8]) -> Result<usize> {
+    if frame.len() > buffer.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "frame exceeds the buffer",
+        ));
+    }
+    buffer[..frame.len()].copy_from_slice(frame);
+    Ok(frame.len())
+}
+
+/// A source reading from a TCP stream, optionally speaking telnet with RFC 2217.
+struct NetworkSource {
+    stream: TcpStream,
+    name: String,
+    telnet: Option<rfc2217::Decoder>,
+    frames: Frames,
+}
+
+impl NetworkSource {
+    fn new(stream: TcpStream, name: String, telnet: Option<rfc2217::Decoder>) -> NetworkSource {
+        NetworkSource {
+            stream,
+            name,
+            telnet,
+            frames: Frames::default(),
+        }
+    }
+}
+
+impl Source for NetworkSource {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        loop {
+            if let Some(frame) = self.frames.next_frame()? {
+                return copy_frame(&frame, buffer);
+            }
+
+            let mut chunk: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+            let n_bytes = match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed by the server",
+                    ))
+                }
+                Ok(n_bytes) => n_bytes,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    return Err(Error::new(ErrorKind::TimedOut, err))
+                }
+                Err(err) => return Err(err),
+            };
+
+            match &mut self.telnet {
+                Some(telnet) => {
+                    let (data, reply) = telnet.decode(&chunk[..n_bytes]);
+                    if !reply.is_empty() {
+                        self.stream.write_all(&reply)?;
+                    }
+                    self.frames.push(&data);
+                }
+                None => self.frames.push(&chunk[..n_bytes]),
+            }
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+}
+
+/// A source reading frames from any reader, such as stdin, a file or a FIFO, until the end of its
+/// input. Frames may span lines or share a line, as with network sources.
+///
+/// The reader runs on its own thread, so that reads time out like reads of a serial port and a
+/// logger waiting for input can still be stopped.
+pub struct InputSource {
+    name: String,
+    chunks: mpsc::Receiver<Result<Vec<u8>>>,
+    frames: Frames,
+    timeout: Duration,
+    finished: bool,
+}
+
+impl InputSource {
+    /// Create a source reading from `reader`, with reads timing out after `timeout`.
+    pub fn new<R: Read + Send + 'static>(name: String, mut reader: R, timeout: Duration) -> Self {
+        let (send, chunks) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name(format!("input-{}", name))
+            .spawn(move || loop {
+                let mut chunk: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+                let result = match reader.read(&mut chunk) {
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    result => result.map(|n_bytes| chunk[..n_bytes].to_vec()),
+                };
+                let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+                if send.send(result).is_err() || done {
+                    break;
+                }
+            });
+        if let Err(err) = spawned {
+            panic!("Failed to spawn input reader thread: {}", err);
+        }
+
+        InputSource {
+            name,
+            chunks,
+            frames: Frames::default(),
+            timeout,
+            finished: false,
+        }
+    }
+}
+
+impl Source for InputSource {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        loop {
+            if let Some(frame) = self.frames.next_frame()? {
+                return copy_frame(&frame, buffer);
+            }
+            if self.finished {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "end of input"));
+            }
+
+            match self.chunks.recv_timeout(self.timeout) {
+                Ok(Ok(chunk)) if !chunk.is_empty() => self.frames.push(&chunk),
+                Ok(Ok(_)) | Err(RecvTimeoutError::Disconnected) => self.finished = true,
+                Ok(Err(err)) => {
+                    self.finished = true;
+                    return Err(err);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::new(ErrorKind::TimedOut, "timed out reading input"))
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Check if a read error means that the source has to be reopened.
+fn is_disconnect(err: &Error) -> bool {
+    !matches!(
+        err.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::InvalidData
+    )
+}
+
+/// A source reopening its connection after it is lost, such as a serial port that is unplugged
+/// and plugged back in, or a TCP connection closed by the server.
+///
+/// Reads fail while the source is disconnected, and the source is reopened on the next read, at
+/// most once per `RECONNECT_INTERVAL`.
+pub struct Reconnecting<F> {
+    name: String,
+    source: Option<Box<dyn Source>>,
+    open: F,
+    last_attempt: Option<Instant>,
+    stop: Option<StopHandle>,
+}
+
+impl<F> Reconnecting<F>
+where
+    F: FnMut() -> Result<Box<dyn Source>> + Send,
+{
+    /// Create a reconnecting source from an opened source and a function opening it again.
+    pub fn new(name: String, source: Box<dyn Source>, open: F) -> Reconnecting<F> {
+        Reconnecting {
+            name,
+            source: Some(source),
+            open,
+            last_attempt: None,
+            stop: None,
+        }
+    }
+}
+
+impl<F> Source for Reconnecting<F>
+where
+    F: FnMut() -> Result<Box<dyn Source>> + Send,
+{
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let source = match &mut self.source {
+            Some(source) => source,
+            None => {
+                if let Some(last_attempt) = self.last_attempt {
+                    thread::sleep(RECONNECT_INTERVAL.saturating_sub(last_attempt.elapsed()));
+                }
+                self.last_attempt = Some(Instant::now());
+                let mut source = (self.open)()?;
+                if let Some(stop) = &self.stop {
+                    source.set_stop_handle(stop.clone());
+                }
+                log::info!("Reconnected to {}", self.name);
+                self.source.insert(source)
+            }
+        };
+
+        let result = source.read(buffer);
+        if let Err(err) = &result {
+            if is_disconnect(err) {
+                log::warn!(
+                    source = self.name.as_str();
+                    "Lost connection to {}: {}", self.name, err
+                );
+                self.source = None;
+            }
+        }
+        result
+    }
+
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn set_stop_handle(&mut self, stop: StopHandle) {
+        if let Some(source) = &mut self.source {
+            source.set_stop_handle(stop.clone());
+        }
+        self.stop = Some(stop);
+    }
+}
+
+/// Minimal telnet client with the com port control option of RFC 2217.
+mod rfc2217 {
+    use super::*;
+
+    const IAC: u8 = 255;
+    const DONT: u8 = 254;
+    const DO: u8 = 253;
+    const WONT: u8 = 252;
+    const WILL: u8 = 251;
+    const SB: u8 = 250;
+    const SE: u8 = 240;
+
+    const BINARY: u8 = 0;
+    const SUPPRESS_GO_AHEAD: u8 = 3;
+    const COM_PORT_OPTION: u8 = 44;
+
+    const SET_BAUDRATE: u8 = 1;
+    const SET_DATASIZE: u8 = 2;
+    const SET_PARITY: u8 = 3;
+    const SET_STOPSIZE: u8 = 4;
+    const SET_CONTROL: u8 = 5;
+
+    /// Get the telnet negotiation and com port settings sent after connecting.
+    pub fn negotiate(baud: u32, serial: &SerialConfig) -> Vec<u8> {
+        let mut buffer = vec![
+            IAC,
+            WILL,
+            BINARY,
+            IAC,
+            DO,
+            BINARY,
+            IAC,
+            DO,
+            SUPPRESS_GO_AHEAD,
+            IAC,
+            WILL,
+            COM_PORT_OPTION,
+        ];
+
+        let parity = match serial.parity {
+            SerialParity::None => 1,
+            SerialParity::Odd => 2,
+            SerialParity::Even => 3,
+        };
+        let flow_control = match serial.flow_control {
+            SerialFlowControl::None => 1,
+            SerialFlowControl::Software => 2,
+            SerialFlowControl::Hardware => 3,
+        };
+        let mut commands = vec![
+            (SET_BAUDRATE, baud.to_be_bytes().to_vec()),
+            (SET_DATASIZE, vec![serial.data_bits]),
+            (SET_PARITY, vec![parity]),
+            (SET_STOPSIZE, vec![serial.stop_bits]),
+            (SET_CONTROL, vec![flow_control]),
+        ];
+        if let Some(dtr) = serial.dtr {
+            commands.push((SET_CONTROL, vec![if dtr { 8 } else { 9 }]));
+        }
+        if let Some(rts) = serial.rts {
+            commands.push((SET_CONTROL, vec![if rts { 11 } else { 12 }]));
+        }
+
+        for (command, value) in commands {
+            buffer.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, command]);
+            for byte in value {
+                buffer.push(byte);
+                if byte == IAC {
+                    buffer.push(IAC);
+                }
+            }
+            buffer.extend_from_slice(&[IAC, SE]);
+        }
+        buffer
+    }
+
+    #[derive(Default)]
+    enum State {
+        #[default]
+        Data,
+        Iac,
+        Option(u8),
+        Sub,
+        SubIac,
+    }
+
+    /// Decoder separating data from telnet commands, which may be split across reads.
+    #[derive(Default)]
+    pub struct Decoder {
+        state: State,
+    }
+
+    impl Decoder {
+        /// Decode received bytes, returning the data and the replies to send to the server.
+        /// Options other than the ones requested on connection are refused, and the responses to
+        /// com port settings are ignored.
+        pub fn decode(&mut self, bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            let mut data = Vec::new();
+            let mut reply = Vec::new();
+            for &byte in bytes {
+                self.state = match (&self.state, byte) {
+                    (State::Data, IAC) => State::Iac,
+                    (State::Data, _) => {
+                        data.push(byte);
+                        State::Data
+                    }
+                    (State::Iac, IAC) => {
+                        data.push(IAC);
+                        State::Data
+                    }
+                    (State::Iac, DO | DONT | WILL | WONT) => State::Option(byte),
+                    (State::Iac, SB) => State::Sub,
+                    (State::Iac, _) => State::Data,
+                    (State::Option(command), option) => {
+                        let accepted =
+                            matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION);
+                        match *command {
+                            DO if !accepted => reply.extend_from_slice(&[IAC, WONT, option]),
+                            WILL if !accepted => reply.extend_from_slice(&[IAC, DONT, option]),
+                            _ => {}
+                        }
+                        State::Data
+                    }
+                    (State::Sub, IAC) => State::SubIac,
+                    (State::Sub, _) => State::Sub,
+                    (State::SubIac, SE) => State::Data,
+                    (State::SubIac, _) => State::Sub,
+                };
+            }
+            (data, reply)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const FRAME: &[u8] = br#"{"0": {"t": 20.0, "h": 50.0, "hi": 19.6}}"#;
+
+    fn read_frame(source: &mut dyn Source) -> Result<Vec<u8>> {
+        let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        let n_bytes = source.read(&mut buffer)?;
+        Ok(buffer[..n_bytes].to_vec())
+    }
+
+    // Test that frames split across TCP reads are joined, and that the source reconnects after
+    // the server closes the connection
+    #[test]
+    fn test_tcp_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = PathBuf::from(format!("tcp://{}", listener.local_addr().unwrap()));
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(&FRAME[..10]).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(50));
+                stream.write_all(&FRAME[10..]).unwrap();
+                stream.write_all(b"\n").unwrap();
+                stream.write_all(FRAME).unwrap();
+            }
+        });
+
+        let addr = SourceAddr::parse(&port);
+        assert!(addr.is_network());
+        let serial = SerialConfig::default();
+        let open = move || addr.open(9600, &serial);
+        let mut source = Reconnecting::new(String::from("test"), open.clone()().unwrap(), open);
+        for _ in 0..2 {
+            assert_eq!(read_frame(&mut source).unwrap(), FRAME);
+            assert_eq!(read_frame(&mut source).unwrap(), FRAME);
+            assert_eq!(
+                read_frame(&mut source).unwrap_err().kind(),
+                ErrorKind::UnexpectedEof
+            );
+        }
+        server.join().unwrap();
+    }
+
+    // Test that invalid data before frames, such as a banner, is skipped without losing the frames
+    // read along with it
+    #[test]
+    fn test_frames_resync() {
+        let mut frames = Frames::default();
+        let mut data = b"ser2net port 2000 device /dev/ttyUSB0\r\n{\"0\": {\"t\": 2".to_vec();
+        data.extend_from_slice(b"\n");
+        data.extend_from_slice(FRAME);
+        data.extend_from_slice(b"\n");
+        data.extend_from_slice(FRAME);
+        frames.push(&data);
+
+        let mut read = Vec::new();
+        let mut errors = 0;
+        loop {
+            match frames.next_frame() {
+                Ok(Some(frame)) => read.push(frame),
+                Ok(None) => break,
+                Err(err) => {
+                    assert_eq!(err.kind(), ErrorKind::InvalidData);
+                    errors += 1;
+                }
+            }
+        }
+        assert_eq!(read, vec![FRAME.to_vec(), FRAME.to_vec()]);
+        assert!(errors > 0);
+        assert!(frames.pending.is_empty());
+    }
+
+    // Test that com port settings are negotiated and telnet commands are stripped from the data
+    #[test]
+    fn test_rfc2217() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = PathBuf::from(format!("rfc2217://{}", listener.local_addr().unwrap()));
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut data = FRAME.to_vec();
+            data.splice(
+                5..5,
+                [255, 253, 1, 255, 250, 44, 101, 0, 0, 37, 128, 255, 240],
+            );
+            stream.write_all(&data).unwrap();
+
+            let mut received = Vec::new();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            let mut buffer = [0; 256];
+            while let Ok(n_bytes) = stream.read(&mut buffer) {
+                if n_bytes == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..n_bytes]);
+            }
+            received
+        });
+
+        let serial: SerialConfig = serde_yaml::from_str("parity: even\ndtr: false").unwrap();
+        let mut source = SourceAddr::parse(&port).open(9600, &serial).unwrap();
+        assert_eq!(read_frame(source.as_mut()).unwrap(), FRAME);
+
+        let received = server.join().unwrap();
+        let settings: &[&[u8]] = &[
+            &[255, 251, 44],
+            &[255, 250, 44, 1, 0, 0, 37, 128, 255, 240],
+            &[255, 250, 44, 3, 3, 255, 240],
+            &[255, 250, 44, 5, 9, 255, 240],
+            // The server asked for the echo option, which is refused
+            &[255, 252, 1],
+        ];
+        for setting in settings {
+            assert!(received
+                .windows(setting.len())
+                .any(|window| window == *setting));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Integration with systemd: service notifications and logging to the journal.
+
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::kv::{self, Key, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use super::Result;
+
+/// Path of the socket of the native journal protocol.
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identifier of log messages in the journal.
+const SYSLOG_IDENTIFIER: &str = "dht-logger";
+
+fn socket_addr(path: &Path) -> Result<SocketAddr> {
+    let name = path.as_os_str().to_string_lossy();
+    match name.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "abstract sockets are only supported on Linux",
+        )),
+        None => SocketAddr::from_pathname(path),
+    }
+}
+
+/// Send service notifications to systemd (see `sd_notify(3)`).
+///
+/// The service is reported ready once the first reading has been received, and the watchdog is
+/// only pinged when readings are received, so that systemd restarts a logger that stopped
+/// receiving data.
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+    ready: bool,
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    /// Create a notifier sending to a Unix datagram socket. Paths starting with `@` refer to
+    /// abstract sockets. The watchdog is pinged at half of the `watchdog` interval.
+    pub fn new(path: &Path, watchdog: Option<Duration>) -> Result<Notifier> {
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr: socket_addr(path)?,
+            watchdog,
+            ready: false,
+            last_ping: None,
+        })
+    }
+
+    /// Create a notifier from the `NOTIFY_SOCKET` and `WATCHDOG_USEC` environment variables set
+    /// by systemd. Returns `None` if the logger does not run as a notify service.
+    pub fn from_env() -> Result<Option<Notifier>> {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+
+        let watchdog_pid = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let watchdog = match env::var("WATCHDOG_USEC") {
+            Ok(_) if watchdog_pid.is_some_and(|pid| pid != std::process::id()) => None,
+            Ok(usec) => {
+                let usec = usec.parse().map_err(|_| {
+                    Error::new(ErrorKind::InvalidInput, "WATCHDOG_USEC must be an integer")
+                })?;
+                Some(Duration::from_micros(usec))
+            }
+            Err(_) => None,
+        };
+
+        Notifier::new(&path, watchdog).map(Some)
+    }
+
+    /// Send a notification with newline separated `KEY=value` assignments.
+    pub fn notify(&self, state: &str) -> Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    /// Notify systemd that a reading was received.
+    pub fn reading(&mut self) -> Result<()> {
+        if !self.ready {
+            self.notify("READY=1\nSTATUS=Receiving sensor readings")?;
+            self.ready = true;
+        }
+
+        if let Some(watchdog) = self.watchdog {
+            let due = self
+                .last_ping
+                .map_or(true, |last_ping| last_ping.elapsed() >= watchdog / 2);
+            if due {
+                self.notify("WATCHDOG=1")?;
+                self.last_ping = Some(Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notify systemd that the logger is shutting down.
+    pub fn stopping(&self) -> Result<()> {
+        self.notify("STOPPING=1")
+    }
+}
+
+/// Collect the key-values of a log record as journal fields.
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: kv::Value<'kvs>,
+    ) -> std::result::Result<(), kv::Error> {
+        let name: String = key
+            .as_str()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect();
+        let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+        if !name.is_empty() {
+            append_field(self.0, name, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Append a field in the native journal protocol format.
+fn append_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+    buffer.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buffer.push(b'\n');
+        buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buffer.push(b'=');
+    }
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(b'\n');
+}
+
+/// Log to the systemd journal with the native journal protocol.
+///
+/// Key-values of log records, such as the `sensor` and `error_kind` of sensor errors, are sent as
+/// structured fields with uppercase names.
+pub struct JournalLogger {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    level: LevelFilter,
+}
+
+impl JournalLogger {
+    /// Create a logger sending to the journal socket at `path`.
+    pub fn new(path: &Path, level: LevelFilter) -> Result<JournalLogger> {
+        Ok(JournalLogger {
+            socket: UnixDatagram::unbound()?,
+            addr: socket_addr(path)?,
+            level,
+        })
+    }
+
+    /// Install a logger sending to the journal as the global logger.
+    pub fn init(level: LevelFilter) -> Result<()> {
+        let logger = JournalLogger::new(Path::new(JOURNAL_SOCKET), level)?;
+        log::set_boxed_logger(Box::new(logger))
+            .map_err(|err| Error::new(ErrorKind::AlreadyExists, err))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    /// Encode a log record in the native journal protocol format.
+    fn encode(&self, record: &Record) -> Vec<u8> {
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+
+        let mut buffer = Vec::new();
+        append_field(&mut buffer, "MESSAGE", &record.args().to_string());
+        append_field(&mut buffer, "PRIORITY", priority);
+        append_field(&mut buffer, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        append_field(&mut buffer, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            append_field(&mut buffer, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            append_field(&mut buffer, "CODE_LINE", &line.to_string());
+        }
+        let _ = record.key_values().visit(&mut Fields(&mut buffer));
+        buffer
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // There is nowhere left to report a failure of the logger itself.
+            let _ = self.socket.send_to_addr(&self.encode(record), &self.addr);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind(name: &str) -> (UnixDatagram, PathBuf) {
+        let path = std::env::temp_dir().join(format!("dht-logger-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        (socket, path)
+    }
+
+    fn recv(socket: &UnixDatagram) -> Option<String> {
+        let mut buffer = [0; 1024];
+        let n_bytes = socket.recv(&mut buffer).ok()?;
+        Some(String::from_utf8_lossy(&buffer[..n_bytes]).into_owned())
+    }
+
+    // Test that readiness is sent once and the watchdog is throttled
+    #[test]
+    fn test_notify() {
+        let (socket, path) = bind("notify");
+        let mut notifier = Notifier::new(&path, Some(Duration::from_secs(60))).unwrap();
+
+        notifier.reading().unwrap();
+        notifier.reading().unwrap();
+        assert!(recv(&socket).unwrap().starts_with("READY=1\n"));
+        assert_eq!(recv(&socket).unwrap(), "WATCHDOG=1");
+        assert_eq!(recv(&socket), None);
+
+        notifier.stopping().unwrap();
+        assert_eq!(recv(&socket).unwrap(), "STOPPING=1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Test that log records are sent to the journal with structured fields
+    #[test]
+    fn test_journal() {
+        let (socket, path) = bind("journal");
+        let logger = JournalLogger::new(&path, LevelFilter::Info).unwrap();
+
+        let kvs: &[(&str, &str)] = &[("sensor", "kitchen"), ("error_kind", "device")];
+        let args = format_args!("Error reading 'kitchen' sensor:\ntimeout");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Warn)
+            .target("dht_logger")
+            .key_values(&kvs)
+            .build();
+        logger.log(&record);
+        let message = recv(&socket).unwrap();
+        assert!(message.contains("PRIORITY=4\n"));
+        assert!(message.contains("SENSOR=kitchen\n"));
+        assert!(message.contains("ERROR_KIND=device\n"));
+        assert!(message.starts_with("MESSAGE\n"));
+
+        let record = Record::builder().level(Level::Debug).build();
+        logger.log(&record);
+        assert_eq!(recv(&socket), None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
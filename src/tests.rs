@@ -1,14 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{prelude::*, Error, ErrorKind};
 use std::net::UdpSocket;
 use std::ptr;
 use std::time::Duration;
 
-use portpicker;
 use serde_json::Value;
 use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
 
 use super::*;
+use crate::units::TemperatureUnit;
 
 // Validate that sensor data can be read
 #[test]
@@ -31,6 +31,40 @@ fn test_empty_sensor() {
     assert!(logger.read_sensor().is_err());
 }
 
+// Validate that readings are iterated until the logger is stopped
+#[test]
+fn test_readings() {
+    let port = Box::new(MockSerialPort::new(2));
+    let logger = DhtLogger::new(port, HashMap::new());
+    let stop = logger.stop_handle();
+
+    let mut count = 0;
+    for reading in logger.readings(10) {
+        assert_eq!(reading.unwrap().data.len(), 2);
+        count += 1;
+        if count == 3 {
+            stop.stop();
+        }
+    }
+    assert_eq!(count, 3);
+}
+
+// Validate that callbacks receive logged readings and can stop the run loop
+#[test]
+fn test_run_callback() {
+    let port = Box::new(MockSerialPort::new(2));
+    let logger = DhtLogger::new(port, HashMap::new());
+
+    let stop = logger.stop_handle();
+    let (send, recv) = std::sync::mpsc::channel();
+    logger.on_reading(move |measurement| {
+        send.send(measurement.data.len()).unwrap();
+        stop.stop();
+    });
+    assert!(logger.run(10).is_ok());
+    assert_eq!(recv.try_iter().collect::<Vec<_>>(), vec![2]);
+}
+
 // Validate that data logged over UDP shows up
 #[test]
 fn test_udp_logger() {
@@ -38,7 +72,7 @@ fn test_udp_logger() {
     let udp_port = portpicker::pick_unused_port().expect("no ports available");
     let udp_addr = format!("127.0.0.1:{}", udp_port);
     let udp_sock = UdpSocket::bind(udp_addr.clone())
-        .expect(&format!("failed to bind to udp address: {}", udp_addr));
+        .unwrap_or_else(|_| panic!("failed to bind to udp address: {}", udp_addr));
     udp_sock
         .set_read_timeout(Some(Duration::from_secs(1)))
         .expect("failed to set read timeout");
@@ -80,6 +114,372 @@ fn test_udp_logger() {
     }
 }
 
+// Validate that aggregating sinks receive aggregates instead of raw readings
+#[test]
+fn test_udp_aggregate() {
+    let udp_port = portpicker::pick_unused_port().expect("no ports available");
+    let udp_addr = format!("127.0.0.1:{}", udp_port);
+    let udp_sock = UdpSocket::bind(udp_addr.clone())
+        .unwrap_or_else(|_| panic!("failed to bind to udp address: {}", udp_addr));
+    udp_sock
+        .set_read_timeout(Some(Duration::from_millis(250)))
+        .expect("failed to set read timeout");
+
+    let logger_config: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+        "udp": [udp_addr],
+        "aggregate": {"interval": 3600, "sinks": ["udp"]},
+    }))
+    .unwrap();
+
+    let data_size = 3;
+    let port = Box::new(MockSerialPort::new(data_size));
+    let logger = DhtLogger::new(port, logger_config);
+    logger.read_sensor_and_log_data(10);
+    logger.read_sensor_and_log_data(10);
+    assert!(logger.flush().is_ok());
+
+    // Both readings are normally in one window, but may straddle a window boundary.
+    let mut buffer: [u8; super::BUFFER_SIZE] = [0; super::BUFFER_SIZE];
+    let mut count = 0;
+    while let Ok((n_bytes, _)) = udp_sock.recv_from(&mut buffer) {
+        let data = serde_json::from_slice::<AggregateSensors>(&buffer[..n_bytes])
+            .expect("failed to deserialize aggregate");
+        assert_eq!(data.data.len(), data_size);
+        assert_eq!(data.end - data.start, chrono::Duration::seconds(3600));
+
+        let sensor = data.data.get("2").unwrap();
+        assert_eq!(sensor.temperature.mean, 2.0);
+        assert_eq!(sensor.temperature.stddev, 0.0);
+        count += sensor.temperature.count;
+    }
+    assert_eq!(count, 2);
+}
+
+// Validate that reloads swap the sinks and keep the old config if the new one is invalid
+#[test]
+fn test_reload() {
+    let bind = || {
+        let udp_port = portpicker::pick_unused_port().expect("no ports available");
+        let udp_addr = format!("127.0.0.1:{}", udp_port);
+        let udp_sock = UdpSocket::bind(udp_addr.clone())
+            .unwrap_or_else(|_| panic!("failed to bind to udp address: {}", udp_addr));
+        udp_sock
+            .set_read_timeout(Some(Duration::from_millis(250)))
+            .expect("failed to set read timeout");
+        (udp_sock, udp_addr)
+    };
+    let (old_sock, old_addr) = bind();
+    let (new_sock, new_addr) = bind();
+    let udp_config = |addr: &str| -> HashMap<String, Value> {
+        serde_json::from_value(serde_json::json!({ "udp": [addr] })).unwrap()
+    };
+
+    let port = Box::new(MockSerialPort::new(1));
+    let logger = DhtLogger::new(port, udp_config(&old_addr));
+    logger.reload(udp_config(&new_addr)).unwrap();
+
+    let invalid: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+        "udp": [old_addr],
+        "aggregate": {"interval": 60, "sinks": ["csv"]},
+    }))
+    .unwrap();
+    assert_eq!(
+        logger.reload(invalid.clone()).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+
+    logger.read_sensor_and_log_data(10);
+    assert!(logger.flush().is_ok());
+    let mut buffer: [u8; super::BUFFER_SIZE] = [0; super::BUFFER_SIZE];
+    assert!(new_sock.recv_from(&mut buffer).is_ok());
+    assert!(old_sock.recv_from(&mut buffer).is_err());
+
+    // An invalid config does not cut the aggregation window short.
+    let mut logger_config = udp_config(&old_addr);
+    logger_config.insert(
+        String::from("aggregate"),
+        serde_json::json!({"interval": 3600, "sinks": ["udp"]}),
+    );
+    let logger = DhtLogger::new(Box::new(MockSerialPort::new(1)), logger_config);
+    logger.read_sensor_and_log_data(10);
+    assert!(logger.reload(invalid).is_err());
+    assert!(old_sock.recv_from(&mut buffer).is_err());
+    assert!(logger.flush().is_ok());
+    assert!(old_sock.recv_from(&mut buffer).is_ok());
+}
+
+// Validate that sinks that cannot be opened and invalid configs are errors instead of panics
+#[test]
+fn test_open_errors() {
+    let open = |logger_config: Value| {
+        let port: Box<dyn SerialPort> = Box::new(MockSerialPort::new(1));
+        DhtLogger::try_from_source(
+            Box::new(port),
+            serde_json::from_value(logger_config).unwrap(),
+        )
+    };
+
+    // 192.0.2.0/24 is reserved for documentation, so it is not an address of this host.
+    let err = open(serde_json::json!({
+        "udp": ["127.0.0.1:9898"],
+        "udp_options": {"bind": "192.0.2.1"},
+    }))
+    .err()
+    .unwrap();
+    assert_ne!(err.kind(), ErrorKind::InvalidData);
+    assert!(
+        err.to_string().contains("Failed to open UDP socket"),
+        "{}",
+        err
+    );
+
+    let err = open(serde_json::json!({"verbose": "yes"})).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+// Validate that input is logged until its end, with frames on one or several lines
+#[test]
+fn test_input() {
+    let input = "{\"a\": {\"t\": 20.0, \"h\": 50.0, \"hi\": 19.6}}\n\
+                 {\"a\": {\"t\": 21.0,\n \"h\": 50.0, \"hi\": 20.6}} \
+                 {\"a\": {\"e\": \"timeout\"}}\n";
+    let source = source::InputSource::new(
+        String::from("capture.jsonl"),
+        std::io::Cursor::new(input),
+        Duration::from_secs(1),
+    );
+    let logger = DhtLogger::from_source(Box::new(source), HashMap::new());
+
+    let (send, recv) = std::sync::mpsc::channel();
+    logger.on_reading(move |measurement| {
+        send.send(measurement.data.contains_key("a")).unwrap();
+    });
+    assert!(logger.run(10).is_ok());
+    assert!(logger.is_finished());
+    assert_eq!(recv.try_iter().collect::<Vec<_>>(), vec![true, true, false]);
+    assert_eq!(logger.readings(10).count(), 0);
+}
+
+// Validate that malformed sensor values from the input become per-sensor errors
+#[test]
+fn test_input_malformed() {
+    let input = "{\"a\": 1}\n{\"a\": {\"e\": 5}, \"b\": {\"t\": 20.0, \"h\": 50.0}}\n";
+    let source = source::InputSource::new(
+        String::from("capture.jsonl"),
+        std::io::Cursor::new(input),
+        Duration::from_secs(1),
+    );
+    let logger = DhtLogger::from_source(Box::new(source), HashMap::new());
+
+    let (send, recv) = std::sync::mpsc::channel();
+    logger.on_reading(move |measurement| {
+        send.send(measurement.clone()).unwrap();
+    });
+    assert!(logger.run(10).is_ok());
+    let measurements: Vec<DhtSensors> = recv.try_iter().collect();
+    assert_eq!(measurements.len(), 2);
+    assert!(measurements[0].errors["a"].contains("must be a JSON mapping"));
+    assert!(measurements[1].errors["a"].contains("must be a string"));
+    assert!(measurements[1].data.contains_key("b"));
+}
+
+// Validate that a config check reads one frame and reports the sink routing
+#[test]
+fn test_check() {
+    let config: DhtLoggerConfig = serde_yaml::from_str(
+        "port: /dev/null\n\
+         baud: 115200\n\
+         logger_config:\n  \
+           csv: /tmp/check.csv\n  \
+           aggregate:\n    interval: 60\n    sinks: [csv]\n",
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    let report = check::read_frame(&config, Box::new(mock_port(2)), 10).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.measurement.data.len(), 2);
+    assert_eq!(
+        report.sinks,
+        vec![
+            (String::from("log"), check::Route::Measurements),
+            (String::from("csv"), check::Route::Aggregates(60)),
+        ]
+    );
+    assert!(report.to_string().contains("csv: aggregates over 60 s"));
+
+    assert!(check::read_frame(&config, Box::new(mock_port(0)), 2).is_err());
+}
+
+// Validate that readings are converted from the input unit to the unit of each sink
+#[test]
+fn test_udp_units() {
+    let udp_port = portpicker::pick_unused_port().expect("no ports available");
+    let udp_addr = format!("127.0.0.1:{}", udp_port);
+    let udp_sock = UdpSocket::bind(udp_addr.clone())
+        .unwrap_or_else(|_| panic!("failed to bind to udp address: {}", udp_addr));
+    udp_sock
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("failed to set read timeout");
+
+    let logger_config: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+        "udp": [udp_addr],
+        "input_unit": "fahrenheit",
+        "sinks": {"udp": {"units": "kelvin"}},
+    }))
+    .unwrap();
+
+    let port = Box::new(MockSerialPort::new(3));
+    let logger = DhtLogger::new(port, logger_config);
+    let measurement = logger.read_sensor().unwrap();
+    assert_eq!(measurement.unit, TemperatureUnit::Celsius);
+    assert!((measurement.data.get("2").unwrap().temperature + 16.667).abs() < 1e-3);
+    logger.log_measurement(measurement).unwrap();
+
+    let mut buffer: [u8; super::BUFFER_SIZE] = [0; super::BUFFER_SIZE];
+    let (n_bytes, _) = udp_sock
+        .recv_from(&mut buffer)
+        .expect("Failed to read data from socket");
+    let data = serde_json::from_slice::<DhtSensorsSerde>(&buffer[..n_bytes])
+        .expect("failed to deserialize");
+    let data = DhtSensors::from_serde(data).unwrap();
+    assert_eq!(data.unit, TemperatureUnit::Kelvin);
+
+    let sensor = data.data.get("2").unwrap();
+    assert!((sensor.temperature - 256.483).abs() < 1e-3);
+    assert!((sensor.heat_index - 256.483).abs() < 1e-3);
+    assert_eq!(sensor.humidity, 2.0);
+}
+
+// Validate that sensor metadata shows up in the CSV columns
+#[test]
+fn test_csv_metadata() {
+    let path = std::env::temp_dir().join(format!("dht-logger-{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let logger_config: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+        "csv": path,
+        "sensors": {
+            "0": {"name": "Kitchen, north", "model": "DHT22", "tags": {"floor": "1"}},
+            "1": {"location": "attic", "tags": {"vent": "yes"}},
+        },
+        "unknown_sensors": "reject",
+    }))
+    .unwrap();
+
+    // The reading of the DHT22 must be valid for its model.
+    let mut port = MockSerialPort::new(3);
+    let heat_index = validation::heat_index(20.0, 50.0);
+    port.data.insert(
+        String::from("0"),
+        DhtDataRaw {
+            t: 20.0,
+            h: 50.0,
+            hi: Some(heat_index),
+            extra: BTreeMap::new(),
+        },
+    );
+    let logger = DhtLogger::new(Box::new(port), logger_config);
+    logger.read_sensor_and_log_data(10);
+    assert!(logger.flush().is_ok());
+
+    let csv = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        lines[0],
+        "timestamp,label,name,location,model,floor,vent,unit,temperature,humidity,heat_index,extra"
+    );
+    assert!(lines[1].ends_with(&format!(
+        ",0,\"Kitchen, north\",,DHT22,1,,celsius,20,50,{},",
+        heat_index
+    )));
+    assert!(lines[2].ends_with(",1,,attic,,,yes,celsius,1,1,1,"));
+}
+
+// Validate that a CSV file with a different header is moved aside instead of appended to
+#[test]
+fn test_csv_rotation() {
+    let dir = std::env::temp_dir();
+    let name = format!("dht-logger-rotation-{}.csv", std::process::id());
+    let path = dir.join(&name);
+    let find_rotated = || -> Vec<PathBuf> {
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|rotated| {
+                let file_name = rotated.file_name().unwrap().to_string_lossy();
+                file_name.starts_with(&format!("{}.", name))
+            })
+            .collect()
+    };
+    let log = |tags: Value| {
+        let logger_config: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+            "csv": path,
+            "sensors": {"0": {"tags": tags}},
+        }))
+        .unwrap();
+        let logger = DhtLogger::new(Box::new(MockSerialPort::new(1)), logger_config);
+        logger.read_sensor_and_log_data(10);
+        assert!(logger.flush().is_ok());
+    };
+    let _ = std::fs::remove_file(&path);
+
+    // Logging again with the same tag keys appends to the file.
+    log(serde_json::json!({"floor": "1"}));
+    log(serde_json::json!({"floor": "2"}));
+    assert!(find_rotated().is_empty());
+    let csv = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(csv.lines().count(), 3);
+
+    // A new tag key changes the columns.
+    log(serde_json::json!({"floor": "2", "room": "kitchen"}));
+    let rotated = find_rotated();
+    assert_eq!(rotated.len(), 1);
+    assert_eq!(std::fs::read_to_string(&rotated[0]).unwrap(), csv);
+    let csv = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(
+        lines[0],
+        "timestamp,label,name,location,model,floor,room,unit,temperature,humidity,heat_index,extra"
+    );
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&rotated[0]).unwrap();
+
+    // A file written before the extra column existed is moved aside as well.
+    let old = "timestamp,label,name,location,model,floor,unit,temperature,humidity,heat_index\n\
+               2024-01-01T00:00:00Z,0,,,,1,celsius,1,1,1\n";
+    std::fs::write(&path, old).unwrap();
+    log(serde_json::json!({"floor": "1"}));
+    let rotated = find_rotated();
+    assert_eq!(rotated.len(), 1);
+    assert_eq!(std::fs::read_to_string(&rotated[0]).unwrap(), old);
+    let csv = std::fs::read_to_string(&path).unwrap();
+    assert!(csv.starts_with(
+        "timestamp,label,name,location,model,floor,unit,temperature,humidity,heat_index,extra\n"
+    ));
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&rotated[0]).unwrap();
+}
+
+// Validate that readings invalid for the sensor model become per-sensor errors
+#[test]
+fn test_model_validation() {
+    let logger_config: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+        "sensors": {"1": {"model": "DHT11"}},
+    }))
+    .unwrap();
+
+    let port = Box::new(MockSerialPort::new(3));
+    let logger = DhtLogger::new(port, logger_config);
+    let measurement = logger.read_sensor().unwrap();
+    assert_eq!(measurement.data.len(), 2);
+    assert!(!measurement.data.contains_key("1"));
+    assert!(measurement.errors.get("1").unwrap().contains("humidity"));
+}
+
 //////////////////
 // TEST HELPERS //
 //////////////////
@@ -102,7 +502,8 @@ impl MockSerialPort {
                 DhtDataRaw {
                     t: value,
                     h: value,
-                    hi: value,
+                    hi: Some(value),
+                    extra: BTreeMap::new(),
                 },
             );
         }
@@ -111,6 +512,10 @@ impl MockSerialPort {
     }
 }
 
+fn mock_port(length: usize) -> Box<dyn SerialPort> {
+    Box::new(MockSerialPort::new(length))
+}
+
 impl Write for MockSerialPort {
     fn write(&mut self, buffer: &[u8]) -> Result<usize> {
         Ok(buffer.len())
@@ -231,7 +636,7 @@ impl SerialPort for MockSerialPort {
         Ok(())
     }
 
-    fn try_clone(&self) -> SerialResult<Box<(dyn SerialPort + 'static)>> {
+    fn try_clone(&self) -> SerialResult<Box<dyn SerialPort + 'static>> {
         Ok(Box::new(self.clone()))
     }
 
@@ -0,0 +1,429 @@
+//! Destinations and socket options of the UDP sink.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::Result;
+
+fn default_multicast_ttl() -> u32 {
+    1
+}
+
+fn default_multicast_loop() -> bool {
+    true
+}
+
+fn default_resolve_interval() -> u64 {
+    300
+}
+
+/// Options of the socket the UDP sink sends from.
+///
+/// Unless `bind` is set, the socket is bound to `[::]:0` as a dual-stack socket sending to both
+/// IPv4 and IPv6 destinations, falling back to `0.0.0.0:0` on hosts without IPv6. Destinations
+/// given by hostname are resolved again every `resolve_interval` seconds. Multicast output is
+/// enabled by listing a multicast group, such as `239.255.98.98:9898`, in `udp`, and subnet
+/// broadcast by listing the broadcast address of the subnet with `broadcast` enabled. `bind` sets
+/// the source address and port, and `interface` the interface multicast data is sent on, either
+/// by its IPv4 address or, on Linux, by its name, which also restricts unicast and broadcast data
+/// to that interface.
+///
+/// Example configuration YAML (inside of `logger_config`):
+/// ```yaml
+/// udp_options:
+///   bind: 192.168.1.10
+///   interface: eth0
+///   multicast_ttl: 1
+///   multicast_loop: true
+///   broadcast: false
+///   resolve_interval: 300
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UdpOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(default = "default_multicast_ttl")]
+    pub multicast_ttl: u32,
+    #[serde(default = "default_multicast_loop")]
+    pub multicast_loop: bool,
+    #[serde(default)]
+    pub broadcast: bool,
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: u64,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        UdpOptions {
+            bind: None,
+            interface: None,
+            multicast_ttl: default_multicast_ttl(),
+            multicast_loop: default_multicast_loop(),
+            broadcast: false,
+            resolve_interval: default_resolve_interval(),
+        }
+    }
+}
+
+impl UdpOptions {
+    /// Parse the `udp_options` section of the logger config, using the defaults if it is missing.
+    /// Returns an `InvalidData` error if the section is invalid.
+    pub fn from_logger_config(logger_config: &HashMap<String, Value>) -> Result<UdpOptions> {
+        let options: UdpOptions = match logger_config.get("udp_options") {
+            Some(options) => serde_json::from_value(options.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.udp_options: {}", err),
+                )
+            })?,
+            None => UdpOptions::default(),
+        };
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Check that the options are supported, returning an `InvalidData` error otherwise.
+    pub fn validate(&self) -> Result<()> {
+        self.bind_addr()?;
+        if self.multicast_ttl > 255 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "udp_options.multicast_ttl must be at most 255, got {}",
+                    self.multicast_ttl
+                ),
+            ));
+        }
+        if self.interface.as_ref().is_some_and(|name| name.is_empty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "udp_options.interface must not be empty",
+            ));
+        }
+        if self.resolve_interval == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "udp_options.resolve_interval must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the address to bind to, if set.
+    pub fn bind_addr(&self) -> Result<Option<SocketAddr>> {
+        let bind = match &self.bind {
+            Some(bind) => bind,
+            None => return Ok(None),
+        };
+        bind.parse::<SocketAddr>()
+            .or_else(|_| bind.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+            .map(Some)
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse udp_options.bind, got value: {}", bind),
+                )
+            })
+    }
+
+    /// Get the interval between resolving destinations given by hostname.
+    pub fn resolve_interval(&self) -> Duration {
+        Duration::from_secs(self.resolve_interval)
+    }
+
+    /// Create a socket with these options.
+    pub fn socket(&self) -> Result<UdpSocket> {
+        match self.bind_addr()? {
+            Some(bind) => self.open(bind),
+            None => self
+                .open(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
+                .or_else(|err| {
+                    log::debug!("Failed to open dual-stack UDP socket, using IPv4: {}", err);
+                    self.open(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+                }),
+        }
+    }
+
+    fn open(&self, bind: SocketAddr) -> Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(bind), Type::DGRAM, Some(Protocol::UDP))?;
+        if bind.is_ipv6() {
+            socket.set_only_v6(false)?;
+            socket.set_multicast_hops_v6(self.multicast_ttl)?;
+            socket.set_multicast_loop_v6(self.multicast_loop)?;
+        }
+        if let Some(interface) = &self.interface {
+            match interface.parse::<Ipv4Addr>() {
+                Ok(addr) => socket.set_multicast_if_v4(&addr)?,
+                Err(_) => bind_device(&socket, interface)?,
+            }
+        }
+        socket.set_multicast_ttl_v4(self.multicast_ttl)?;
+        socket.set_multicast_loop_v4(self.multicast_loop)?;
+        socket.set_broadcast(self.broadcast)?;
+        socket.bind(&bind.into())?;
+        Ok(socket.into())
+    }
+}
+
+/// Get the address to send to from a socket, which is the IPv4-mapped IPv6 address of IPv4
+/// destinations on dual-stack sockets.
+pub fn target(socket: &UdpSocket, addr: SocketAddr) -> Result<SocketAddr> {
+    Ok(match (socket.local_addr()?, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    })
+}
+
+#[derive(Clone, Debug)]
+enum Destination {
+    Addr(SocketAddr),
+    Host {
+        name: String,
+        addr: Option<SocketAddr>,
+    },
+}
+
+/// Parse a UDP destination, either `IP:PORT`, with IPv6 addresses in brackets, or `HOST:PORT`.
+fn parse_destination(destination: &str) -> Result<Destination> {
+    if let Ok(addr) = destination.parse::<SocketAddr>() {
+        return Ok(Destination::Addr(addr));
+    }
+
+    match destination.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok() =>
+        {
+            Ok(Destination::Host {
+                name: destination.to_owned(),
+                addr: None,
+            })
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Failed to parse IP:PORT or HOST:PORT, got value: {}",
+                destination
+            ),
+        )),
+    }
+}
+
+/// Check that a UDP destination can be parsed, returning an `InvalidData` error otherwise.
+pub fn validate_destination(destination: &str) -> Result<()> {
+    parse_destination(destination).map(|_| ())
+}
+
+/// Destinations of the UDP sink, resolving hostnames periodically.
+///
+/// A hostname is sent to the first address it resolves to in the address family of the socket,
+/// or to its first address if it has none in that family. If resolving a hostname fails, the
+/// failure is logged and the previously resolved address is used until the next attempt.
+#[derive(Clone, Debug)]
+pub struct Destinations {
+    destinations: Vec<Destination>,
+    interval: Duration,
+    resolved: Option<Instant>,
+}
+
+impl Destinations {
+    /// Parse a list of destinations (see `validate_destination`).
+    pub fn parse<S: AsRef<str>>(destinations: &[S], interval: Duration) -> Result<Destinations> {
+        Ok(Destinations {
+            destinations: destinations
+                .iter()
+                .map(|destination| parse_destination(destination.as_ref()))
+                .collect::<Result<_>>()?,
+            interval,
+            resolved: None,
+        })
+    }
+
+    /// Get the current address of every destination, resolving hostnames if they are due.
+    /// `ipv6` selects the address family preferred for hostnames, which is the family of the
+    /// socket sending to them.
+    pub fn addrs(&mut self, ipv6: bool) -> Vec<SocketAddr> {
+        if self
+            .resolved
+            .map_or(true, |resolved| resolved.elapsed() >= self.interval)
+        {
+            self.resolve(ipv6);
+        }
+        self.destinations
+            .iter()
+            .filter_map(|destination| match destination {
+                Destination::Addr(addr) => Some(*addr),
+                Destination::Host { addr, .. } => *addr,
+            })
+            .collect()
+    }
+
+    fn resolve(&mut self, ipv6: bool) {
+        self.resolved = Some(Instant::now());
+        for destination in self.destinations.iter_mut() {
+            if let Destination::Host { name, addr } = destination {
+                match name.to_socket_addrs().map(|addrs| select(addrs, ipv6)) {
+                    Ok(Some(resolved)) => {
+                        if *addr != Some(resolved) {
+                            log::debug!("Resolved UDP destination {} to {}", name, resolved);
+                        }
+                        *addr = Some(resolved);
+                    }
+                    Ok(None) => log::warn!("No address found for UDP destination {}", name),
+                    Err(err) => log::warn!("Failed to resolve UDP destination {}: {}", name, err),
+                }
+            }
+        }
+    }
+}
+
+/// Select the first address of a family, or the first address if there is none of that family.
+fn select<I: IntoIterator<Item = SocketAddr>>(addrs: I, ipv6: bool) -> Option<SocketAddr> {
+    let mut first = None;
+    for addr in addrs {
+        if addr.is_ipv6() == ipv6 {
+            return Some(addr);
+        }
+        first = first.or(Some(addr));
+    }
+    first
+}
+
+impl From<Vec<SocketAddr>> for Destinations {
+    fn from(addrs: Vec<SocketAddr>) -> Self {
+        Destinations {
+            destinations: addrs.into_iter().map(Destination::Addr).collect(),
+            interval: Duration::from_secs(default_resolve_interval()),
+            resolved: None,
+        }
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "interfaces can only be selected by address on this platform, got {}",
+            interface
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // Test that the socket is created with the configured options and sends from the bind address
+    #[test]
+    fn test_udp_options() {
+        let options: UdpOptions =
+            serde_yaml::from_str("bind: 127.0.0.1\nmulticast_ttl: 4\nbroadcast: true").unwrap();
+        assert!(options.multicast_loop);
+        let socket = options.socket().unwrap();
+        assert_eq!(socket.multicast_ttl_v4().unwrap(), 4);
+        assert!(socket.broadcast().unwrap());
+
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        socket
+            .send_to(b"{}", listener.local_addr().unwrap())
+            .unwrap();
+        let mut buffer = [0; 8];
+        let (_, addr) = listener.recv_from(&mut buffer).unwrap();
+        assert_eq!(addr, socket.local_addr().unwrap());
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let invalid = [
+            "bind: localhost",
+            "multicast_ttl: 256",
+            "interface: ''",
+            "resolve_interval: 0",
+        ];
+        for invalid in invalid {
+            let options: UdpOptions = serde_yaml::from_str(invalid).unwrap();
+            assert_eq!(
+                options.validate().unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+    }
+
+    // Test that destinations of both address families and hostnames are reached from the default
+    // dual-stack socket
+    #[test]
+    fn test_destinations() {
+        let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v6 = UdpSocket::bind("[::1]:0").unwrap();
+        // The hostname may resolve to an address of either family, so it is sent to a dual-stack
+        // listener.
+        let dual = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        dual.set_only_v6(false).unwrap();
+        dual.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).into())
+            .unwrap();
+        let dual: UdpSocket = dual.into();
+        for listener in [&v4, &v6, &dual] {
+            listener
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+        }
+
+        let names = [
+            v4.local_addr().unwrap().to_string(),
+            v6.local_addr().unwrap().to_string(),
+            format!("localhost:{}", dual.local_addr().unwrap().port()),
+        ];
+        let mut destinations = Destinations::parse(&names, Duration::from_secs(60)).unwrap();
+        let socket = UdpOptions::default().socket().unwrap();
+        let addrs = destinations.addrs(socket.local_addr().unwrap().is_ipv6());
+        assert_eq!(addrs.len(), 3);
+
+        for addr in addrs {
+            socket
+                .send_to(b"{}", target(&socket, addr).unwrap())
+                .unwrap();
+        }
+        let mut buffer = [0; 8];
+        for listener in [&v4, &v6, &dual] {
+            assert_eq!(listener.recv_from(&mut buffer).unwrap().0, 2);
+        }
+
+        for invalid in ["localhost", "::1", "[::1]", "host:port", ":9898"] {
+            assert!(validate_destination(invalid).is_err());
+        }
+    }
+
+    // Test that hostnames resolve to an address of the family of the socket if they have one
+    #[test]
+    fn test_select() {
+        let v4: SocketAddr = "127.0.0.1:9898".parse().unwrap();
+        let v6: SocketAddr = "[::1]:9898".parse().unwrap();
+        assert_eq!(select([v4, v6], true), Some(v6));
+        assert_eq!(select([v6, v4], false), Some(v4));
+        assert_eq!(select([v4], true), Some(v4));
+        assert_eq!(select([v6], false), Some(v6));
+        assert_eq!(select([], false), None);
+
+        let mut destinations =
+            Destinations::parse(&["localhost:9898"], Duration::from_secs(60)).unwrap();
+        assert!(destinations.addrs(false)[0].is_ipv4());
+    }
+}
@@ -0,0 +1,91 @@
+//! Temperature units of DHT sensor readings.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Unit of the temperature and heat index of DHT sensor readings.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    /// Convert a temperature in this unit to another unit.
+    pub fn convert(self, value: f32, to: TemperatureUnit) -> f32 {
+        let celsius = match self {
+            TemperatureUnit::Celsius => value,
+            TemperatureUnit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            TemperatureUnit::Kelvin => value - 273.15,
+        };
+
+        match to {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => celsius + 273.15,
+        }
+    }
+
+    /// Convert a temperature difference, such as a standard deviation, to another unit.
+    pub fn convert_difference(self, value: f32, to: TemperatureUnit) -> f32 {
+        value * to.degree_scale() / self.degree_scale()
+    }
+
+    fn degree_scale(self) -> f32 {
+        match self {
+            TemperatureUnit::Celsius | TemperatureUnit::Kelvin => 1.0,
+            TemperatureUnit::Fahrenheit => 9.0 / 5.0,
+        }
+    }
+}
+
+impl fmt::Display for TemperatureUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit",
+            TemperatureUnit::Kelvin => "kelvin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = String;
+
+    fn from_str(unit: &str) -> std::result::Result<Self, Self::Err> {
+        match unit.to_lowercase().as_str() {
+            "celsius" | "c" => Ok(TemperatureUnit::Celsius),
+            "fahrenheit" | "f" => Ok(TemperatureUnit::Fahrenheit),
+            "kelvin" | "k" => Ok(TemperatureUnit::Kelvin),
+            _ => Err(format!("unknown temperature unit: {}", unit)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    // Test conversions between all units
+    #[test]
+    fn test_convert() {
+        use TemperatureUnit::*;
+        assert_close(Celsius.convert(100.0, Fahrenheit), 212.0);
+        assert_close(Fahrenheit.convert(32.0, Celsius), 0.0);
+        assert_close(Kelvin.convert(273.15, Fahrenheit), 32.0);
+        assert_close(Fahrenheit.convert(212.0, Kelvin), 373.15);
+        assert_close(Celsius.convert(21.5, Celsius), 21.5);
+        assert_close(Celsius.convert_difference(5.0, Fahrenheit), 9.0);
+        assert_close(Fahrenheit.convert_difference(9.0, Kelvin), 5.0);
+    }
+}
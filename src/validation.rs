@@ -0,0 +1,181 @@
+//! Validation of DHT sensor readings against the specifications of the sensor model.
+
+use super::messages::SensorData;
+use super::sensors::SensorModel;
+use super::units::TemperatureUnit;
+
+/// Maximum difference in degrees Celsius between the reported and the computed heat index.
+pub const HEAT_INDEX_TOLERANCE: f32 = 1.0;
+
+/// Measurement specifications of a DHT sensor model.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ModelSpec {
+    /// Valid temperature range in degrees Celsius.
+    pub temperature: (f32, f32),
+    /// Valid relative humidity range in percent.
+    pub humidity: (f32, f32),
+    /// Temperature resolution in degrees Celsius.
+    pub temperature_resolution: f32,
+    /// Relative humidity resolution in percent.
+    pub humidity_resolution: f32,
+}
+
+impl SensorModel {
+    /// Get the measurement specifications of the sensor model.
+    pub fn spec(&self) -> ModelSpec {
+        match self {
+            SensorModel::Dht11 => ModelSpec {
+                temperature: (0.0, 50.0),
+                humidity: (20.0, 90.0),
+                temperature_resolution: 1.0,
+                humidity_resolution: 1.0,
+            },
+            SensorModel::Dht22 | SensorModel::Am2302 => ModelSpec {
+                temperature: (-40.0, 80.0),
+                humidity: (0.0, 100.0),
+                temperature_resolution: 0.1,
+                humidity_resolution: 0.1,
+            },
+        }
+    }
+}
+
+/// Compute the heat index in degrees Celsius the same way as the Adafruit DHT library.
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = TemperatureUnit::Celsius.convert(temperature, TemperatureUnit::Fahrenheit);
+    let h = humidity;
+
+    let mut hi = 0.5 * (t + 61.0 + ((t - 68.0) * 1.2) + (h * 0.094));
+    if hi > 79.0 {
+        hi = -42.379 + 2.049_015_2 * t + 10.143_331 * h
+            - 0.224_755_4 * t * h
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * h * h
+            + 0.001_228_74 * t * t * h
+            + 0.000_852_82 * t * h * h
+            - 0.000_001_99 * t * t * h * h;
+
+        if h < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= ((13.0 - h) * 0.25) * ((17.0 - (t - 95.0).abs()) * 0.058_82).sqrt();
+        } else if h > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += ((h - 85.0) * 0.1) * ((87.0 - t) * 0.2);
+        }
+    }
+
+    TemperatureUnit::Fahrenheit.convert(hi, TemperatureUnit::Celsius)
+}
+
+fn check_range(field: &str, value: f32, range: (f32, f32)) -> std::result::Result<(), String> {
+    if value.is_nan() {
+        return Err(format!("{} is NaN", field));
+    }
+
+    if !(range.0..=range.1).contains(&value) {
+        return Err(format!(
+            "{} {} outside of valid range [{}, {}]",
+            field, value, range.0, range.1
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_resolution(field: &str, value: f32, resolution: f32) -> std::result::Result<(), String> {
+    let steps = value / resolution;
+    if (steps - steps.round()).abs() > 1e-2 {
+        return Err(format!(
+            "{} {} does not match sensor resolution {}",
+            field, value, resolution
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validate a reading in its input unit, returning a description of the first problem found.
+///
+/// Every reading must have non-NaN values and a relative humidity in [0, 100]. If the sensor model
+/// is known, the reading must also be within the range of the model, match the resolution of the
+/// model and have a heat index consistent with the temperature and humidity. The resolution of
+/// the temperature is only checked for readings in Celsius.
+pub fn validate(
+    data: &SensorData,
+    unit: TemperatureUnit,
+    model: Option<SensorModel>,
+) -> std::result::Result<(), String> {
+    let celsius = data.to_unit(unit, TemperatureUnit::Celsius);
+    check_range("temperature", celsius.temperature, (f32::MIN, f32::MAX))?;
+    check_range("humidity", celsius.humidity, (0.0, 100.0))?;
+    check_range("heat index", celsius.heat_index, (f32::MIN, f32::MAX))?;
+
+    let spec = match model {
+        Some(model) => model.spec(),
+        None => return Ok(()),
+    };
+
+    check_range("temperature", celsius.temperature, spec.temperature)?;
+    check_range("humidity", celsius.humidity, spec.humidity)?;
+    check_resolution("humidity", data.humidity, spec.humidity_resolution)?;
+    if unit == TemperatureUnit::Celsius {
+        check_resolution("temperature", data.temperature, spec.temperature_resolution)?;
+    }
+
+    let expected = heat_index(celsius.temperature, celsius.humidity);
+    if (celsius.heat_index - expected).abs() > HEAT_INDEX_TOLERANCE {
+        return Err(format!(
+            "heat index {} inconsistent with temperature {} and humidity {}, expected {}",
+            celsius.heat_index, celsius.temperature, celsius.humidity, expected
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(temperature: f32, humidity: f32) -> SensorData {
+        SensorData {
+            temperature,
+            humidity,
+            heat_index: heat_index(temperature, humidity),
+            ..Default::default()
+        }
+    }
+
+    // Test the heat index against values from the NOAA heat index table
+    #[test]
+    fn test_heat_index() {
+        let f = |t: f32| TemperatureUnit::Fahrenheit.convert(t, TemperatureUnit::Celsius);
+        assert!((heat_index(f(90.0), 50.0) - f(95.0)).abs() < 0.5);
+        assert!((heat_index(f(100.0), 40.0) - f(109.0)).abs() < 0.5);
+        assert!((heat_index(20.0, 50.0) - 19.6).abs() < 0.5);
+    }
+
+    // Test validation of readings against the sensor model
+    #[test]
+    fn test_validate() {
+        use TemperatureUnit::*;
+        let dht11 = Some(SensorModel::Dht11);
+        let dht22 = Some(SensorModel::Dht22);
+
+        assert!(validate(&data(21.0, 45.0), Celsius, dht11).is_ok());
+        assert!(validate(&data(21.3, 45.0), Celsius, dht11).is_err());
+        assert!(validate(&data(21.3, 45.5), Celsius, dht22).is_ok());
+        assert!(validate(&data(21.3, 45.5), Celsius, dht11).is_err());
+        assert!(validate(&data(-10.0, 45.0), Celsius, dht11).is_err());
+        assert!(validate(&data(-10.0, 45.0), Celsius, dht22).is_ok());
+        assert!(validate(&data(21.3, 101.0), Celsius, None).is_err());
+        assert!(validate(&data(f32::NAN, 45.0), Celsius, None).is_err());
+
+        let mut reading = data(30.0, 70.0);
+        assert!(validate(&reading, Celsius, dht22).is_ok());
+        reading.heat_index = 30.0;
+        assert!(validate(&reading, Celsius, None).is_ok());
+        assert!(validate(&reading, Celsius, dht22).is_err());
+
+        let reading = data(30.0, 70.0).to_unit(Celsius, Fahrenheit);
+        assert!(validate(&reading, Fahrenheit, dht22).is_ok());
+    }
+}
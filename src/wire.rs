@@ -0,0 +1,570 @@
+//! Versioned wire format of the data sent over UDP.
+//!
+//! Every payload is an object with an envelope of three fields next to the data:
+//! * `v`: Version of the wire format.
+//! * `src`: Id of the DHT logger that sent the payload, the hostname unless configured.
+//! * `seq`: Sequence number of the payload, counting up from 0 when the sink is created, so that
+//!   listeners can detect lost packets and restarts of the logger.
+//!
+//! The data is tagged by `k`, which is `m` for a measurement (`DhtSensorsSerde`) and `a` for an
+//! aggregate (`AggregateSensors`), with the fields of the data at the top level of the object.
+//!
+//! # Encodings
+//! Payloads are encoded as JSON by default, or as CBOR or MessagePack for smaller payloads on
+//! metered links. The first byte of a payload is its content type, so that a listener can accept
+//! all encodings: JSON payloads start with `{` as before, and binary payloads start with
+//! `CONTENT_CBOR` or `CONTENT_MSGPACK`, followed by the encoded object. MessagePack objects are
+//! encoded as maps with field names. Binary encodings need version 1 or newer.
+//!
+//! # Authentication
+//! With `wire.auth` configured, payloads are wrapped in a signed payload with an HMAC-SHA256 tag,
+//! a timestamp and a nonce (see the `auth` module). A `Decoder` with the same key verifies the
+//! tag and rejects unsigned and replayed payloads.
+//!
+//! # Versions
+//! * `0`: The unversioned format, a bare `DhtSensorsSerde` or `AggregateSensors` without envelope.
+//! * `1`: The envelope described above.
+//!
+//! # Compatibility policy
+//! * Adding a field that can be omitted does not change the version. Decoders ignore fields they
+//!   do not know, so older listeners keep working.
+//! * Removing a field, renaming it or changing its meaning increments the version.
+//! * Decoders accept every version up to their own, and reject newer versions with an
+//!   `InvalidData` error. To upgrade, update the listeners first, or set `wire.version` in the
+//!   logger config to the oldest version the listeners support until all of them are updated.
+//! * Version 1 keeps the fields of version 0 at the top level, so listeners deserializing a
+//!   `DhtSensorsSerde` directly keep decoding measurements.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::auth::{AuthConfig, Signer, Verifier, CONTENT_SIGNED};
+use super::messages::*;
+use super::Result;
+
+/// Version of the wire format sent by default, and the newest version that can be decoded.
+pub const VERSION: u32 = 1;
+
+/// Content type of JSON payloads, which is the first byte of every JSON object.
+pub const CONTENT_JSON: u8 = b'{';
+/// Content type of CBOR payloads.
+pub const CONTENT_CBOR: u8 = 0x01;
+/// Content type of MessagePack payloads.
+pub const CONTENT_MSGPACK: u8 = 0x02;
+
+fn default_version() -> u32 {
+    VERSION
+}
+
+/// Encoding of the payloads.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    Msgpack,
+}
+
+impl Encoding {
+    /// Get the encoding of a content type byte.
+    pub fn from_content_type(content_type: u8) -> Option<Encoding> {
+        match content_type {
+            CONTENT_JSON => Some(Encoding::Json),
+            CONTENT_CBOR => Some(Encoding::Cbor),
+            CONTENT_MSGPACK => Some(Encoding::Msgpack),
+            _ => None,
+        }
+    }
+
+    /// Get the content type byte of the encoding.
+    pub fn content_type(&self) -> u8 {
+        match self {
+            Encoding::Json => CONTENT_JSON,
+            Encoding::Cbor => CONTENT_CBOR,
+            Encoding::Msgpack => CONTENT_MSGPACK,
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let invalid = |err: String| Error::new(ErrorKind::InvalidData, err);
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            Encoding::Cbor => {
+                let mut data = vec![CONTENT_CBOR];
+                ciborium::ser::into_writer(value, &mut data)
+                    .map_err(|err| invalid(err.to_string()))?;
+                Ok(data)
+            }
+            Encoding::Msgpack => {
+                let mut data = vec![CONTENT_MSGPACK];
+                let mut serializer = rmp_serde::Serializer::new(&mut data).with_struct_map();
+                value
+                    .serialize(&mut serializer)
+                    .map_err(|err| invalid(err.to_string()))?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Deserialize a payload, including the content type byte of binary encodings.
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        let invalid = |err: String| Error::new(ErrorKind::InvalidData, err);
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(data)?),
+            Encoding::Cbor => {
+                ciborium::de::from_reader(&data[1..]).map_err(|err| invalid(err.to_string()))
+            }
+            Encoding::Msgpack => {
+                rmp_serde::from_slice(&data[1..]).map_err(|err| invalid(err.to_string()))
+            }
+        }
+    }
+}
+
+/// Configuration of the wire format.
+///
+/// Example configuration YAML (inside of `logger_config`):
+/// ```yaml
+/// wire:
+///   # Send an older version for listeners that were not updated yet.
+///   version: 1
+///   # Id of the logger in the envelope, defaulting to the hostname.
+///   source_id: greenhouse
+///   # One of json, cbor or msgpack.
+///   encoding: cbor
+///   # Sign payloads (see `AuthConfig`).
+///   auth:
+///     key_file: /etc/dht-logger/udp.key
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WireConfig {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<String>,
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+}
+
+impl Default for WireConfig {
+    fn default() -> Self {
+        WireConfig {
+            version: default_version(),
+            source_id: None,
+            encoding: Encoding::default(),
+            auth: None,
+        }
+    }
+}
+
+impl WireConfig {
+    /// Parse the `wire` section of the logger config, using the defaults if it is missing.
+    /// Returns an `InvalidData` error if the section is invalid.
+    pub fn from_logger_config(logger_config: &HashMap<String, Value>) -> Result<WireConfig> {
+        let config: WireConfig = match logger_config.get("wire") {
+            Some(config) => serde_json::from_value(config.clone()).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse logger.wire: {}", err),
+                )
+            })?,
+            None => WireConfig::default(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the version and encoding can be sent and that the key of `auth` is usable,
+    /// returning an `InvalidData` error otherwise.
+    pub fn validate(&self) -> Result<()> {
+        if self.version > VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "wire.version must be at most {}, got {}",
+                    VERSION, self.version
+                ),
+            ));
+        }
+        if self.version == 0 && self.encoding != Encoding::Json {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "wire.version 0 only supports the json encoding",
+            ));
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate()?;
+        }
+        Ok(())
+    }
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("dht-logger"))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "k")]
+enum Body {
+    #[serde(rename = "m")]
+    Measurement(DhtSensorsSerde),
+    #[serde(rename = "a")]
+    Aggregate(AggregateSensors),
+}
+
+/// The fields of a payload needed to tell its version and kind, whatever the version.
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    v: Option<u64>,
+    #[serde(default)]
+    start: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Envelope {
+    v: u32,
+    src: String,
+    seq: u64,
+    #[serde(flatten)]
+    body: Body,
+}
+
+/// Data decoded from a payload.
+#[derive(Clone, Debug)]
+pub enum Payload {
+    Measurement(DhtSensors),
+    Aggregate(AggregateSensors),
+}
+
+/// A decoded payload with its envelope. The source id and sequence number are only set from
+/// version 1 on. `signed` is set for payloads that were verified with a key.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    pub version: u32,
+    pub encoding: Encoding,
+    pub signed: bool,
+    pub source: Option<String>,
+    pub sequence: Option<u64>,
+    pub payload: Payload,
+}
+
+/// Encode data in the wire format, numbering the payloads.
+pub struct Encoder {
+    version: u32,
+    encoding: Encoding,
+    source: String,
+    sequence: u64,
+    signer: Option<Signer>,
+}
+
+impl Encoder {
+    /// Create an encoder for the version, encoding and source id of a config. Payloads are not
+    /// signed; use `from_config` to load the key of `auth`.
+    pub fn new(config: &WireConfig) -> Encoder {
+        Encoder {
+            version: config.version,
+            encoding: config.encoding,
+            source: config.source_id.clone().unwrap_or_else(hostname),
+            sequence: 0,
+            signer: None,
+        }
+    }
+
+    /// Create an encoder for a config, signing payloads if `auth` is configured.
+    pub fn from_config(config: &WireConfig) -> Result<Encoder> {
+        let encoder = Encoder::new(config);
+        Ok(match &config.auth {
+            Some(auth) => encoder.with_signer(Signer::new(&auth.key()?)),
+            None => encoder,
+        })
+    }
+
+    /// Sign every payload with a signer.
+    pub fn with_signer(mut self, signer: Signer) -> Encoder {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Encode a measurement of all DHT sensors.
+    pub fn encode_measurement(&mut self, measurement: &DhtSensors) -> Result<Vec<u8>> {
+        self.encode(Body::Measurement(DhtSensorsSerde::from(measurement)))
+    }
+
+    /// Encode an aggregate of measurements over a time window.
+    pub fn encode_aggregate(&mut self, aggregate: &AggregateSensors) -> Result<Vec<u8>> {
+        self.encode(Body::Aggregate(aggregate.clone()))
+    }
+
+    fn encode(&mut self, body: Body) -> Result<Vec<u8>> {
+        let data = match self.version {
+            0 => match body {
+                Body::Measurement(data) => self.encoding.serialize(&data)?,
+                Body::Aggregate(data) => self.encoding.serialize(&data)?,
+            },
+            _ => self.encoding.serialize(&Envelope {
+                v: self.version,
+                src: self.source.clone(),
+                seq: self.sequence,
+                body,
+            })?,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(match &mut self.signer {
+            Some(signer) => signer.sign(&data),
+            None => data,
+        })
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Encoder::new(&WireConfig::default())
+    }
+}
+
+/// Decode payloads, verifying signed payloads if created with a key.
+#[derive(Default)]
+pub struct Decoder {
+    verifier: Option<Verifier>,
+}
+
+impl Decoder {
+    /// Create a decoder for unsigned payloads.
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Create a decoder only accepting payloads signed with the key of the verifier.
+    pub fn with_verifier(verifier: Verifier) -> Decoder {
+        Decoder {
+            verifier: Some(verifier),
+        }
+    }
+
+    /// Create a decoder for a config, verifying payloads if `auth` is configured.
+    pub fn from_config(config: &WireConfig) -> Result<Decoder> {
+        Ok(match &config.auth {
+            Some(auth) => Decoder::with_verifier(Verifier::new(&auth.key()?, auth.max_age())),
+            None => Decoder::new(),
+        })
+    }
+
+    /// Decode a payload of any supported version and encoding. Returns a `PermissionDenied`
+    /// error for signed payloads without a key, and for unsigned or invalid payloads with a key.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Packet> {
+        let denied = |reason: &str| Err(Error::new(ErrorKind::PermissionDenied, reason));
+        let signed = data.first() == Some(&CONTENT_SIGNED);
+        let data = match &mut self.verifier {
+            Some(verifier) if signed => verifier.verify(data)?,
+            Some(_) => return denied("payload is not signed"),
+            None if signed => return denied("payload is signed, but there is no key to verify it"),
+            None => data,
+        };
+
+        let mut packet = decode_payload(data)?;
+        packet.signed = signed;
+        Ok(packet)
+    }
+}
+
+/// Decode an unsigned payload of any supported version and encoding.
+pub fn decode(data: &[u8]) -> Result<Packet> {
+    Decoder::new().decode(data)
+}
+
+fn decode_payload(data: &[u8]) -> Result<Packet> {
+    let encoding = data
+        .first()
+        .and_then(|content_type| Encoding::from_content_type(*content_type))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown content type"))?;
+    let probe: Probe = encoding.deserialize(data)?;
+    let version = probe.v.unwrap_or(0);
+    if version > VERSION as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported wire version: {}", version),
+        ));
+    }
+
+    let (source, sequence, body) = if version == 0 {
+        let body = if probe.start.is_some() {
+            Body::Aggregate(encoding.deserialize(data)?)
+        } else {
+            Body::Measurement(encoding.deserialize(data)?)
+        };
+        (None, None, body)
+    } else {
+        let envelope: Envelope = encoding.deserialize(data)?;
+        (Some(envelope.src), Some(envelope.seq), envelope.body)
+    };
+
+    let payload = match body {
+        Body::Measurement(data) => Payload::Measurement(DhtSensors::from_serde(data)?),
+        Body::Aggregate(data) => Payload::Aggregate(data),
+    };
+    Ok(Packet {
+        version: version as u32,
+        encoding,
+        signed: false,
+        source,
+        sequence,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::units::TemperatureUnit;
+
+    fn measurement() -> DhtSensors {
+        let data = SensorData {
+            temperature: 21.5,
+            humidity: 40.0,
+            heat_index: 21.0,
+            extra: BTreeMap::from([(String::from("p"), 1013.0)]),
+        };
+        DhtSensors {
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            unit: TemperatureUnit::Celsius,
+            data: HashMap::from([(String::from("kitchen"), data)]),
+            errors: HashMap::new(),
+        }
+    }
+
+    fn aggregate() -> AggregateSensors {
+        let field = FieldAggregate {
+            count: 2,
+            min: 1.0,
+            max: 3.0,
+            mean: 2.0,
+            stddev: 1.0,
+        };
+        let data = SensorAggregate {
+            temperature: field,
+            humidity: field,
+            heat_index: field,
+            extra: BTreeMap::new(),
+        };
+        AggregateSensors {
+            start: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            end: Utc.timestamp_opt(1_700_000_060, 0).unwrap(),
+            unit: TemperatureUnit::Celsius,
+            data: HashMap::from([(String::from("kitchen"), data)]),
+        }
+    }
+
+    // Test that measurements and aggregates round trip through every version and encoding
+    #[test]
+    fn test_round_trip() {
+        let encodings = [Encoding::Json, Encoding::Cbor, Encoding::Msgpack];
+        let configs = (0..=VERSION).flat_map(|version| {
+            encodings.map(|encoding| WireConfig {
+                version,
+                source_id: Some(String::from("pi")),
+                encoding,
+                ..Default::default()
+            })
+        });
+        for config in configs.filter(|config| config.validate().is_ok()) {
+            let version = config.version;
+            let mut encoder = Encoder::new(&config);
+
+            let data = encoder.encode_measurement(&measurement()).unwrap();
+            assert_eq!(data[0], config.encoding.content_type());
+            let packet = decode(&data).unwrap();
+            assert_eq!(packet.version, version);
+            assert_eq!(packet.encoding, config.encoding);
+            match packet.payload {
+                Payload::Measurement(data) => assert_eq!(data.data, measurement().data),
+                payload => panic!("expected a measurement, got {:?}", payload),
+            }
+
+            let packet = decode(&encoder.encode_aggregate(&aggregate()).unwrap()).unwrap();
+            match packet.payload {
+                Payload::Aggregate(data) => assert_eq!(data.data, aggregate().data),
+                payload => panic!("expected an aggregate, got {:?}", payload),
+            }
+            if version > 0 {
+                assert_eq!(packet.source.as_deref(), Some("pi"));
+                assert_eq!(packet.sequence, Some(1));
+            } else {
+                assert!(packet.source.is_none() && packet.sequence.is_none());
+            }
+        }
+    }
+
+    // Test that listeners of the unversioned format still decode measurements, and that newer
+    // versions are rejected
+    #[test]
+    fn test_compatibility() {
+        let data = Encoder::default()
+            .encode_measurement(&measurement())
+            .unwrap();
+        let serde: DhtSensorsSerde = serde_json::from_slice(&data).unwrap();
+        assert_eq!(
+            DhtSensors::from_serde(serde).unwrap().data,
+            measurement().data
+        );
+
+        let mut value: Value = serde_json::from_slice(&data).unwrap();
+        value["v"] = Value::from(VERSION + 1);
+        let err = decode(&serde_json::to_vec(&value).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let config = WireConfig {
+            version: VERSION + 1,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = WireConfig {
+            version: 0,
+            encoding: Encoding::Cbor,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(decode(&[0xff, 0x00]).is_err());
+    }
+
+    // Test that signed payloads decode with the key only, and that replays are rejected
+    #[test]
+    fn test_signed() {
+        let key = b"0123456789abcdef";
+        let mut encoder = Encoder::default().with_signer(Signer::new(key));
+        let data = encoder.encode_measurement(&measurement()).unwrap();
+        assert_eq!(data[0], CONTENT_SIGNED);
+
+        let mut decoder = Decoder::with_verifier(Verifier::new(key, Duration::from_secs(30)));
+        let packet = decoder.decode(&data).unwrap();
+        assert!(packet.signed);
+        assert_eq!(packet.encoding, Encoding::Json);
+
+        let denied =
+            |result: Result<Packet>| result.unwrap_err().kind() == ErrorKind::PermissionDenied;
+        assert!(denied(decoder.decode(&data)));
+        assert!(denied(decode(&data)));
+        let unsigned = Encoder::default()
+            .encode_measurement(&measurement())
+            .unwrap();
+        assert!(denied(decoder.decode(&unsigned)));
+    }
+}